@@ -0,0 +1,310 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::node::Node;
+use crate::TrieError;
+use ccrypto::{blake256, BLAKE_NULL_RLP};
+use cdb::HashDB;
+use primitives::{Bytes, H256};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// The nodes of a trie which are not in another trie.
+///
+/// A follower which has the trie of `old_root` imports the delta to have the complete trie of `root`.
+/// The nodes are identified by their hashes, so the delta is verified against `root` on import.
+#[derive(Clone, Debug, Eq, PartialEq, RlpEncodable, RlpDecodable)]
+pub struct Delta {
+    pub root: H256,
+    pub nodes: Vec<Bytes>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum DeltaError {
+    /// A node of the new trie is neither in the delta nor in the database.
+    MissingNode(H256),
+    /// A node in the delta is not a valid node.
+    InvalidNode(H256),
+    /// A node in the delta is not in the new trie.
+    UnusedNode(H256),
+}
+
+impl fmt::Display for DeltaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeltaError::MissingNode(hash) => write!(f, "Delta missing the node: {}", hash),
+            DeltaError::InvalidNode(hash) => write!(f, "Delta has an invalid node: {}", hash),
+            DeltaError::UnusedNode(hash) => write!(f, "Delta has a node not in the trie: {}", hash),
+        }
+    }
+}
+
+/// The part of the old trie at a position in the new trie.
+#[derive(Clone)]
+enum OldNode {
+    /// The node starts at the position.
+    Hash(H256),
+    /// The position is in the middle of the partial path of a branch.
+    Branch(Vec<u8>, Box<[Option<H256>; 16]>),
+}
+
+impl Delta {
+    /// Collects the nodes of the trie of `new_root` which are not in the trie of `old_root`.
+    /// Both tries should be in `db`. The subtrees which are the same in both tries are skipped without reading them.
+    pub fn export(db: &dyn HashDB, old_root: &H256, new_root: &H256) -> crate::Result<Self> {
+        let mut nodes = Vec::new();
+        if *new_root != BLAKE_NULL_RLP {
+            let old = if *old_root == BLAKE_NULL_RLP {
+                None
+            } else {
+                Some(OldNode::Hash(*old_root))
+            };
+            export_aux(db, new_root, old, &mut nodes)?;
+        }
+        Ok(Delta {
+            root: *new_root,
+            nodes,
+        })
+    }
+
+    /// Verifies the delta and inserts its nodes into `db`.
+    ///
+    /// Every node of the new trie must be in the delta or in `db`, and every node in the delta must be in the new trie.
+    /// The nodes already in `db` are assumed to have their subtrees complete, as they do if `db` has the old trie.
+    /// Nothing is inserted if the verification fails.
+    ///
+    /// A node is inserted once for each of its occurrences in the new trie, as `TrieDBMut` writes it, so that a node
+    /// shared by several subtrees stays while any of them is left in a trie with pruning.
+    pub fn import(&self, db: &mut dyn HashDB) -> Result<(), DeltaError> {
+        let nodes: HashMap<H256, &Bytes> = self.nodes.iter().map(|node| (blake256(node), node)).collect();
+        let mut used = HashSet::new();
+        let mut missing = Vec::new();
+
+        let mut stack = Vec::new();
+        if self.root != BLAKE_NULL_RLP {
+            stack.push(self.root);
+        }
+        while let Some(hash) = stack.pop() {
+            if db.contains(&hash) {
+                used.insert(hash);
+                continue
+            }
+            let node_rlp = nodes.get(&hash).ok_or(DeltaError::MissingNode(hash))?;
+            used.insert(hash);
+            match Node::try_decoded(node_rlp) {
+                Ok(Some(Node::Branch(_, children))) => stack.extend(children.iter().flatten()),
+                Ok(Some(Node::Leaf(..))) => {}
+                Ok(None) | Err(_) => return Err(DeltaError::InvalidNode(hash)),
+            }
+            missing.push(hash);
+        }
+        if let Some(hash) = nodes.keys().find(|hash| !used.contains(hash)) {
+            return Err(DeltaError::UnusedNode(*hash))
+        }
+
+        for hash in missing {
+            db.insert(nodes[&hash]);
+        }
+        Ok(())
+    }
+}
+
+/// Pushes the node of `hash` and its descendants which are not in the old trie, starting from the node.
+fn export_aux(db: &dyn HashDB, hash: &H256, old: Option<OldNode>, nodes: &mut Vec<Bytes>) -> crate::Result<()> {
+    if let Some(OldNode::Hash(old_hash)) = &old {
+        if old_hash == hash {
+            return Ok(())
+        }
+    }
+    let node_rlp = db.get(hash).ok_or(TrieError::IncompleteDatabase(*hash))?;
+    let children = match Node::decoded(&node_rlp) {
+        Some(Node::Branch(partial, children)) => Some((descend(db, old, &partial.to_vec())?, children)),
+        _ => None,
+    };
+    nodes.push(node_rlp);
+
+    if let Some((old, children)) = children {
+        for (index, child) in children.iter().enumerate() {
+            if let Some(child) = child {
+                export_aux(db, child, descend(db, old.clone(), &[index as u8])?, nodes)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the part of the old trie at `nibbles` below `old`.
+fn descend(db: &dyn HashDB, old: Option<OldNode>, nibbles: &[u8]) -> crate::Result<Option<OldNode>> {
+    if nibbles.is_empty() {
+        return Ok(old)
+    }
+    let (partial, children) = match old {
+        None => return Ok(None),
+        Some(OldNode::Hash(hash)) => {
+            let node_rlp = db.get(&hash).ok_or(TrieError::IncompleteDatabase(hash))?;
+            match Node::decoded(&node_rlp) {
+                Some(Node::Branch(partial, children)) => (partial.to_vec(), children),
+                _ => return Ok(None),
+            }
+        }
+        Some(OldNode::Branch(partial, children)) => (partial, children),
+    };
+
+    let common = partial.iter().zip(nibbles).take_while(|(a, b)| a == b).count();
+    if common == nibbles.len() {
+        Ok(Some(OldNode::Branch(partial[common..].to_vec(), children)))
+    } else if common < partial.len() {
+        Ok(None)
+    } else {
+        descend(db, children[nibbles[common] as usize].map(OldNode::Hash), &nibbles[common + 1..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{IterableTrie, Trie, TrieDB, TrieDBMut, TrieMut};
+    use cdb::MemoryDB;
+    use rlp::{decode, encode};
+
+    fn populate(db: &mut MemoryDB, root: &mut H256, range: std::ops::Range<u32>) {
+        let mut t = if *root == H256::zero() {
+            TrieDBMut::new(db, root)
+        } else {
+            TrieDBMut::from_existing(db, root).unwrap()
+        };
+        for i in range {
+            t.insert(&i.to_be_bytes(), &i.to_le_bytes()).unwrap();
+        }
+    }
+
+    fn items(db: &MemoryDB, root: &H256) -> Vec<(H256, Bytes)> {
+        TrieDB::try_new(db, root).unwrap().iter().unwrap().map(|item| item.unwrap()).collect()
+    }
+
+    #[test]
+    fn import_completes_new_trie() {
+        let mut old_db = MemoryDB::new();
+        let mut old_root = H256::zero();
+        populate(&mut old_db, &mut old_root, 0..500);
+
+        let mut db = old_db.clone();
+        let mut new_root = old_root;
+        {
+            let mut t = TrieDBMut::from_existing(&mut db, &mut new_root).unwrap();
+            t.insert(&0u32.to_be_bytes(), b"modified").unwrap();
+            t.remove(&1u32.to_be_bytes()).unwrap();
+        }
+        populate(&mut db, &mut new_root, 500..520);
+
+        let delta = Delta::export(&db, &old_root, &new_root).unwrap();
+        assert!(delta.nodes.len() < db.keys().len() - old_db.keys().len() + 100);
+        assert_eq!(decode::<Delta>(&encode(&delta)).unwrap(), delta);
+
+        delta.import(&mut old_db).unwrap();
+        assert!(TrieDB::try_new(&old_db, &new_root).unwrap().is_complete());
+        assert_eq!(items(&old_db, &new_root), items(&db, &new_root));
+    }
+
+    #[test]
+    fn import_into_empty_db() {
+        let mut db = MemoryDB::new();
+        let mut root = H256::zero();
+        populate(&mut db, &mut root, 0..100);
+
+        let delta = Delta::export(&db, &BLAKE_NULL_RLP, &root).unwrap();
+        assert_eq!(delta.nodes.len(), db.keys().len());
+
+        let mut new_db = MemoryDB::new();
+        delta.import(&mut new_db).unwrap();
+        assert_eq!(new_db.keys(), db.keys());
+        assert_eq!(items(&new_db, &root), items(&db, &root));
+        assert_eq!(Delta::export(&db, &root, &root).unwrap().nodes, Vec::<Bytes>::new());
+    }
+
+    #[test]
+    fn reject_invalid_delta() {
+        let mut old_db = MemoryDB::new();
+        let mut old_root = H256::zero();
+        populate(&mut old_db, &mut old_root, 0..100);
+        let mut db = old_db.clone();
+        let mut new_root = old_root;
+        populate(&mut db, &mut new_root, 100..110);
+        let delta = Delta::export(&db, &old_root, &new_root).unwrap();
+
+        let mut wrong_root = delta.clone();
+        wrong_root.root = blake256(b"wrong root");
+        assert_eq!(wrong_root.import(&mut old_db.clone()), Err(DeltaError::MissingNode(wrong_root.root)));
+
+        let mut tampered = delta.clone();
+        let last = tampered.nodes.last_mut().unwrap();
+        *last.last_mut().unwrap() ^= 1;
+        let mut tampered_db = old_db.clone();
+        assert!(tampered.import(&mut tampered_db).is_err());
+        assert_eq!(tampered_db.keys(), old_db.keys());
+
+        let mut extra = delta.clone();
+        let leaf = Node::encoded(Node::Leaf(crate::nibbleslice::NibbleSlice::new(&[0x12]), b"extra"));
+        extra.nodes.push(leaf.clone());
+        assert_eq!(extra.import(&mut old_db.clone()), Err(DeltaError::UnusedNode(blake256(&leaf))));
+
+        let mut invalid = delta.clone();
+        invalid.root = blake256(b"invalid");
+        invalid.nodes.push(b"invalid".to_vec());
+        assert_eq!(invalid.import(&mut old_db.clone()), Err(DeltaError::InvalidNode(invalid.root)));
+
+        delta.import(&mut old_db).unwrap();
+        assert!(TrieDB::try_new(&old_db, &new_root).unwrap().is_complete());
+    }
+
+    #[test]
+    fn import_counts_shared_nodes() {
+        use crate::hasher::Identity;
+
+        // The subtrees under the children 1 and 2 of the root are the same.
+        let key = |first: u8, i: u8| {
+            let mut key = [i; 32];
+            key[0] = (first << 4) | i;
+            key
+        };
+        let mut db = MemoryDB::new();
+        let mut root = H256::zero();
+        {
+            let mut t = TrieDBMut::<Identity>::new_with_hasher(&mut db, &mut root);
+            for i in 0..4 {
+                t.insert(&key(1, i), b"value").unwrap();
+                t.insert(&key(2, i), b"value").unwrap();
+            }
+        }
+        assert!(db.keys().values().any(|count| *count == 2));
+
+        let mut new_db = MemoryDB::new();
+        Delta::export(&db, &BLAKE_NULL_RLP, &root).unwrap().import(&mut new_db).unwrap();
+        assert_eq!(new_db.keys(), db.keys());
+
+        // Pruning one of the subtrees keeps the other.
+        {
+            let mut t =
+                TrieDBMut::<Identity>::from_existing_with_hasher(&mut new_db, &mut root).unwrap().with_pruning();
+            for i in 0..4 {
+                t.insert(&key(1, i), b"changed").unwrap();
+            }
+        }
+        let t = TrieDB::<Identity>::try_new_with_hasher(&new_db, &root).unwrap();
+        assert!(t.is_complete());
+        assert_eq!(t.get(&key(2, 0)).unwrap(), Some(b"value".to_vec()));
+    }
+}
@@ -0,0 +1,398 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::iterator::key_from_nibbles;
+use crate::node::Node;
+use crate::TrieError;
+use ccrypto::BLAKE_NULL_RLP;
+use cdb::HashDB;
+use primitives::{Bytes, H256};
+
+/// A change of a leaf between two tries. The keys are the hashed keys.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum Change {
+    Added(H256, Bytes),
+    Removed(H256, Bytes),
+    /// The key, the old value and the new value.
+    Modified(H256, Bytes, Bytes),
+}
+
+impl Change {
+    pub fn key(&self) -> &H256 {
+        match self {
+            Change::Added(key, _) | Change::Removed(key, _) | Change::Modified(key, ..) => key,
+        }
+    }
+}
+
+/// A node seen from some depth of the trie.
+/// The nibbles of the partial path above the depth are dropped, and `hash` is `None` if any nibble is dropped.
+struct View {
+    hash: Option<H256>,
+    node: ViewNode,
+}
+
+enum ViewNode {
+    Leaf(Vec<u8>, Bytes),
+    Branch(Vec<u8>, Box<[Option<H256>; 16]>),
+}
+
+fn load(db: &dyn HashDB, hash: &H256) -> crate::Result<Option<View>> {
+    if *hash == BLAKE_NULL_RLP {
+        return Ok(None)
+    }
+    let node_rlp = db.get(hash).ok_or(TrieError::IncompleteDatabase(*hash))?;
+    let node = match Node::decoded(&node_rlp) {
+        Some(Node::Leaf(partial, value)) => ViewNode::Leaf(partial.to_vec(), value.to_vec()),
+        Some(Node::Branch(partial, children)) => ViewNode::Branch(partial.to_vec(), children),
+        None => return Ok(None),
+    };
+    Ok(Some(View {
+        hash: Some(*hash),
+        node,
+    }))
+}
+
+fn load_child(db: &dyn HashDB, child: Option<H256>) -> crate::Result<Option<View>> {
+    match child {
+        Some(hash) => load(db, &hash),
+        None => Ok(None),
+    }
+}
+
+/// Returns the changes of the leaves from the trie of `old_root` to the trie of `new_root`, in the order of the keys.
+///
+/// The subtrees which are the same in both tries are skipped by comparing their hashes,
+/// so only the nodes on the paths of the changes are read.
+pub fn diff(db: &dyn HashDB, old_root: &H256, new_root: &H256) -> crate::Result<Vec<Change>> {
+    let mut changes = Vec::new();
+    diff_aux(db, &mut Vec::new(), load(db, old_root)?, load(db, new_root)?, &mut changes)?;
+    Ok(changes)
+}
+
+/// Compares the views of the old and the new trie at `path`.
+fn diff_aux(
+    db: &dyn HashDB,
+    path: &mut Vec<u8>,
+    old: Option<View>,
+    new: Option<View>,
+    changes: &mut Vec<Change>,
+) -> crate::Result<()> {
+    let (old, new) = match (old, new) {
+        (None, None) => return Ok(()),
+        (Some(old), None) => return all_leaves(db, path, old, Change::Removed, changes),
+        (None, Some(new)) => return all_leaves(db, path, new, Change::Added, changes),
+        (Some(old), Some(new)) => (old, new),
+    };
+    if old.hash.is_some() && old.hash == new.hash {
+        return Ok(())
+    }
+
+    match (old.node, new.node) {
+        (ViewNode::Leaf(partial, value), node) => diff_leaf(
+            db,
+            path,
+            (partial, value),
+            View {
+                hash: new.hash,
+                node,
+            },
+            true,
+            changes,
+        ),
+        (node, ViewNode::Leaf(partial, value)) => diff_leaf(
+            db,
+            path,
+            (partial, value),
+            View {
+                hash: old.hash,
+                node,
+            },
+            false,
+            changes,
+        ),
+        (ViewNode::Branch(old_partial, old_children), ViewNode::Branch(new_partial, new_children)) => {
+            let common = old_partial.iter().zip(&new_partial).take_while(|(a, b)| a == b).count();
+            let depth = path.len();
+            path.extend(&old_partial[..common]);
+
+            let result = if common < old_partial.len() && common < new_partial.len() {
+                // The branches have nothing in common.
+                let old = View {
+                    hash: None,
+                    node: ViewNode::Branch(old_partial[common..].to_vec(), old_children),
+                };
+                let new = View {
+                    hash: None,
+                    node: ViewNode::Branch(new_partial[common..].to_vec(), new_children),
+                };
+                if old_partial[common] < new_partial[common] {
+                    all_leaves(db, path, old, Change::Removed, changes)
+                        .and_then(|_| all_leaves(db, path, new, Change::Added, changes))
+                } else {
+                    all_leaves(db, path, new, Change::Added, changes)
+                        .and_then(|_| all_leaves(db, path, old, Change::Removed, changes))
+                }
+            } else {
+                diff_children(
+                    db,
+                    path,
+                    (&old_partial[common..], old_children),
+                    (&new_partial[common..], new_children),
+                    changes,
+                )
+            };
+            path.truncate(depth);
+            result
+        }
+    }
+}
+
+/// Compares the branches at `path`, at least one of which has no more partial path.
+fn diff_children(
+    db: &dyn HashDB,
+    path: &mut Vec<u8>,
+    old: (&[u8], Box<[Option<H256>; 16]>),
+    new: (&[u8], Box<[Option<H256>; 16]>),
+    changes: &mut Vec<Change>,
+) -> crate::Result<()> {
+    let (old_partial, old_children) = old;
+    let (new_partial, new_children) = new;
+    for index in 0..16u8 {
+        if old_partial.is_empty()
+            && new_partial.is_empty()
+            && old_children[index as usize] == new_children[index as usize]
+        {
+            // Skip the same subtree without reading it.
+            continue
+        }
+        path.push(index);
+        // A branch which has more partial path is compared with the child of the other branch.
+        let old = match old_partial.split_first() {
+            None => load_child(db, old_children[index as usize])?,
+            Some((first, rest)) if *first == index => Some(View {
+                hash: None,
+                node: ViewNode::Branch(rest.to_vec(), old_children.clone()),
+            }),
+            Some(_) => None,
+        };
+        let new = match new_partial.split_first() {
+            None => load_child(db, new_children[index as usize])?,
+            Some((first, rest)) if *first == index => Some(View {
+                hash: None,
+                node: ViewNode::Branch(rest.to_vec(), new_children.clone()),
+            }),
+            Some(_) => None,
+        };
+        diff_aux(db, path, old, new, changes)?;
+        path.pop();
+    }
+    Ok(())
+}
+
+/// Compares a leaf with a view at `path`.
+fn diff_leaf(
+    db: &dyn HashDB,
+    path: &mut Vec<u8>,
+    leaf: (Vec<u8>, Bytes),
+    other: View,
+    leaf_is_old: bool,
+    changes: &mut Vec<Change>,
+) -> crate::Result<()> {
+    let (partial, value) = leaf;
+    let mut nibbles = path.clone();
+    nibbles.extend(partial);
+    let key = key_from_nibbles(&nibbles);
+
+    let mut others = Vec::new();
+    collect_leaves(db, path, other, &mut others)?;
+
+    let mut leaf_change = Some(if leaf_is_old {
+        Change::Removed(key, value.clone())
+    } else {
+        Change::Added(key, value.clone())
+    });
+    for (other_key, other_value) in others {
+        if other_key == key {
+            leaf_change = None;
+            if other_value != value {
+                changes.push(if leaf_is_old {
+                    Change::Modified(key, value.clone(), other_value)
+                } else {
+                    Change::Modified(key, other_value, value.clone())
+                });
+            }
+            continue
+        }
+        if key < other_key {
+            changes.extend(leaf_change.take());
+        }
+        changes.push(if leaf_is_old {
+            Change::Added(other_key, other_value)
+        } else {
+            Change::Removed(other_key, other_value)
+        });
+    }
+    changes.extend(leaf_change);
+    Ok(())
+}
+
+fn all_leaves(
+    db: &dyn HashDB,
+    path: &mut Vec<u8>,
+    view: View,
+    change: fn(H256, Bytes) -> Change,
+    changes: &mut Vec<Change>,
+) -> crate::Result<()> {
+    let mut leaves = Vec::new();
+    collect_leaves(db, path, view, &mut leaves)?;
+    changes.extend(leaves.into_iter().map(|(key, value)| change(key, value)));
+    Ok(())
+}
+
+/// Pushes the leaves under the view at `path` to `leaves`, in the order of the keys.
+fn collect_leaves(
+    db: &dyn HashDB,
+    path: &mut Vec<u8>,
+    view: View,
+    leaves: &mut Vec<(H256, Bytes)>,
+) -> crate::Result<()> {
+    let depth = path.len();
+    match view.node {
+        ViewNode::Leaf(partial, value) => {
+            path.extend(partial);
+            leaves.push((key_from_nibbles(path), value));
+        }
+        ViewNode::Branch(partial, children) => {
+            path.extend(partial);
+            for (index, child) in children.iter().enumerate() {
+                if let Some(child) = load_child(db, *child)? {
+                    path.push(index as u8);
+                    collect_leaves(db, path, child, leaves)?;
+                    path.pop();
+                }
+            }
+        }
+    }
+    path.truncate(depth);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TrieDB, TrieDBMut, TrieMut};
+    use ccrypto::blake256;
+    use cdb::MemoryDB;
+    use rand::{rngs::StdRng, Rng};
+    use std::collections::{BTreeMap, HashSet};
+
+    fn nodes(db: &MemoryDB, root: &H256) -> HashSet<Bytes> {
+        use crate::proof::CryptoStructure;
+
+        let t = TrieDB::try_new(db, root).unwrap();
+        let (_, proof) = t.make_range_proof(&H256::zero(), &H256::from([0xff; 32])).unwrap();
+        proof.0.into_iter().collect()
+    }
+
+    fn expected_changes(old: &BTreeMap<H256, Bytes>, new: &BTreeMap<H256, Bytes>) -> Vec<Change> {
+        let keys: BTreeMap<_, _> = old.keys().chain(new.keys()).map(|key| (*key, ())).collect();
+        keys.keys()
+            .filter_map(|key| match (old.get(key), new.get(key)) {
+                (Some(old), None) => Some(Change::Removed(*key, old.clone())),
+                (None, Some(new)) => Some(Change::Added(*key, new.clone())),
+                (Some(old), Some(new)) if old != new => Some(Change::Modified(*key, old.clone(), new.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn random_changes() {
+        let seed = [0u8; 32];
+        let mut rng: StdRng = rand::SeedableRng::from_seed(seed);
+
+        for _ in 0..20 {
+            let mut db = MemoryDB::new();
+            let mut old_root = H256::zero();
+            let mut old = BTreeMap::new();
+            {
+                let mut t = TrieDBMut::new(&mut db, &mut old_root);
+                for _ in 0..rng.gen_range(0, 100) {
+                    let (key, value) = (rng.gen::<u8>().to_be_bytes(), rng.gen::<u32>().to_be_bytes());
+                    t.insert(&key, &value).unwrap();
+                    old.insert(blake256(key), value.to_vec());
+                }
+            }
+
+            let mut new_root = old_root;
+            let mut new = old.clone();
+            {
+                let mut t = TrieDBMut::from_existing(&mut db, &mut new_root).unwrap();
+                for _ in 0..rng.gen_range(0, 30) {
+                    let key = rng.gen::<u8>().to_be_bytes();
+                    if rng.gen() {
+                        let value = rng.gen::<u32>().to_be_bytes();
+                        t.insert(&key, &value).unwrap();
+                        new.insert(blake256(key), value.to_vec());
+                    } else {
+                        t.remove(&key).unwrap();
+                        new.remove(&blake256(key));
+                    }
+                }
+            }
+
+            assert_eq!(diff(&db, &old_root, &new_root).unwrap(), expected_changes(&old, &new));
+            assert_eq!(diff(&db, &new_root, &old_root).unwrap(), expected_changes(&new, &old));
+        }
+    }
+
+    #[test]
+    fn skip_same_subtrees() {
+        let mut db = MemoryDB::new();
+        let mut old_root = H256::zero();
+        {
+            let mut t = TrieDBMut::new(&mut db, &mut old_root);
+            for i in 0..1000u32 {
+                t.insert(&i.to_be_bytes(), &i.to_be_bytes()).unwrap();
+            }
+        }
+        let mut new_root = old_root;
+        {
+            let mut t = TrieDBMut::from_existing(&mut db, &mut new_root).unwrap();
+            t.insert(&0u32.to_be_bytes(), b"modified").unwrap();
+            t.insert(&1000u32.to_be_bytes(), b"added").unwrap();
+            t.remove(&1u32.to_be_bytes()).unwrap();
+        }
+
+        // Only the nodes which are not in both tries are needed.
+        let old_nodes = nodes(&db, &old_root);
+        let new_nodes = nodes(&db, &new_root);
+        let mut changed_db = MemoryDB::new();
+        for node in old_nodes.symmetric_difference(&new_nodes) {
+            changed_db.insert(node);
+        }
+
+        let mut changes = vec![
+            Change::Modified(blake256(0u32.to_be_bytes()), 0u32.to_be_bytes().to_vec(), b"modified".to_vec()),
+            Change::Added(blake256(1000u32.to_be_bytes()), b"added".to_vec()),
+            Change::Removed(blake256(1u32.to_be_bytes()), 1u32.to_be_bytes().to_vec()),
+        ];
+        changes.sort_by_key(|change| *change.key());
+        assert_eq!(diff(&changed_db, &old_root, &new_root).unwrap(), changes);
+        assert_eq!(diff(&db, &old_root, &old_root).unwrap(), vec![]);
+    }
+}
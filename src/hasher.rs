@@ -0,0 +1,130 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::TrieError;
+use primitives::H256;
+
+/// Hashes the keys of a trie into the paths of their leaves.
+pub trait KeyHasher {
+    /// Returns `TrieError::InvalidKey` if the hasher does not accept `key`.
+    fn hash(key: &[u8]) -> crate::Result<H256>;
+}
+
+/// Blake2b-256, the default key hasher.
+pub struct Blake256;
+
+impl KeyHasher for Blake256 {
+    fn hash(key: &[u8]) -> crate::Result<H256> {
+        Ok(ccrypto::blake256(key))
+    }
+}
+
+/// Keccak-256, the hasher of Ethereum.
+pub struct Keccak256;
+
+impl KeyHasher for Keccak256 {
+    fn hash(key: &[u8]) -> crate::Result<H256> {
+        Ok(ccrypto::keccak256(key))
+    }
+}
+
+/// SHA-256.
+pub struct Sha256;
+
+impl KeyHasher for Sha256 {
+    fn hash(key: &[u8]) -> crate::Result<H256> {
+        Ok(ccrypto::sha256(key))
+    }
+}
+
+/// Uses the keys as the paths, for the keys which are already 32-byte hashes.
+/// Rejects the keys which are not 32 bytes long.
+pub struct Identity;
+
+impl KeyHasher for Identity {
+    fn hash(key: &[u8]) -> crate::Result<H256> {
+        if key.len() != 32 {
+            return Err(TrieError::InvalidKey(key.to_vec()))
+        }
+        Ok(H256::from(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::triehash::{trie_root, trie_root_with_hasher};
+    use crate::{Trie, TrieDB, TrieDBMut, TrieMut};
+    use cdb::MemoryDB;
+
+    fn items() -> Vec<(Vec<u8>, Vec<u8>)> {
+        (0..100u32).map(|i| (i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec())).collect()
+    }
+
+    #[test]
+    fn keccak_trie() {
+        let mut memdb = MemoryDB::new();
+        let mut root = H256::zero();
+        {
+            let mut t = TrieDBMut::<Keccak256>::new_with_hasher(&mut memdb, &mut root);
+            for (key, value) in items() {
+                t.insert(&key, &value).unwrap();
+            }
+        }
+        assert_eq!(root, trie_root_with_hasher::<Keccak256, _, _, _>(items()).unwrap());
+        assert_ne!(root, trie_root(items()));
+
+        let t = TrieDB::<Keccak256>::try_new_with_hasher(&memdb, &root).unwrap();
+        assert_eq!(t.get(&7u32.to_be_bytes()).unwrap(), Some(7u32.to_le_bytes().to_vec()));
+        let new_root = t.root_after(vec![(100u32.to_be_bytes(), Some(b"new".to_vec()))]).unwrap();
+        let mut expected = items();
+        expected.push((100u32.to_be_bytes().to_vec(), b"new".to_vec()));
+        assert_eq!(new_root, trie_root_with_hasher::<Keccak256, _, _, _>(expected).unwrap());
+    }
+
+    #[test]
+    fn prehashed_keys() {
+        let prehashed: Vec<_> =
+            items().into_iter().map(|(key, value)| (Blake256::hash(&key).unwrap().to_vec(), value)).collect();
+        assert_eq!(trie_root_with_hasher::<Identity, _, _, _>(prehashed.clone()).unwrap(), trie_root(items()));
+
+        let mut memdb = MemoryDB::new();
+        let mut root = H256::zero();
+        let mut t = TrieDBMut::<Identity>::new_with_hasher(&mut memdb, &mut root);
+        for (key, value) in &prehashed {
+            t.insert(key, value).unwrap();
+        }
+        t.commit();
+        assert_eq!(*t.root(), trie_root(items()));
+    }
+
+    #[test]
+    fn identity_rejects_short_keys() {
+        assert_eq!(Identity::hash(b"short"), Err(TrieError::InvalidKey(b"short".to_vec())));
+        assert_eq!(
+            trie_root_with_hasher::<Identity, _, _, _>(vec![(b"short", b"value")]),
+            Err(TrieError::InvalidKey(b"short".to_vec()))
+        );
+
+        let mut memdb = MemoryDB::new();
+        let mut root = H256::zero();
+        let mut t = TrieDBMut::<Identity>::new_with_hasher(&mut memdb, &mut root);
+        assert_eq!(t.insert(b"short", b"value"), Err(TrieError::InvalidKey(b"short".to_vec())));
+        assert_eq!(t.get(b"short"), Err(TrieError::InvalidKey(b"short".to_vec())));
+        assert!(t.apply_batch(vec![([1; 32].to_vec(), Some(b"value")), (b"short".to_vec(), Some(b"value"))]).is_err());
+        assert!(t.is_empty());
+    }
+}
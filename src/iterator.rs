@@ -0,0 +1,399 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::nibbleslice::NibbleSlice;
+use crate::node::Node as RlpNode;
+use crate::TrieError;
+use ccrypto::BLAKE_NULL_RLP;
+use cdb::{DBValue, HashDB, MemoryDB};
+use primitives::{Bytes, H256};
+use std::cmp::Ordering;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Status {
+    Entering,
+    AtChild(usize),
+    Exiting,
+}
+
+/// A node on the path from the root to the current position of the iterator.
+struct Crumb {
+    node_rlp: DBValue,
+    // Nibbles from the root to this node, not including the partial path of the node itself.
+    prefix: Vec<u8>,
+    status: Status,
+}
+
+enum Step {
+    Yield(H256, DBValue),
+    Descend(H256, Vec<u8>),
+    Pop,
+}
+
+/// Iterator over the leaves of a trie, in the order of their hashed keys.
+///
+/// Each item is a pair of the hashed key and the value of a leaf.
+/// Use `seek` to resume the iteration from a given hashed key.
+///
+/// # Example
+/// ```
+/// use cdb::*;
+/// use merkle_trie::*;
+/// use primitives::H256;
+///
+/// let mut memdb = MemoryDB::new();
+/// let mut root = H256::zero();
+/// TrieFactory::create(&mut memdb, &mut root).insert(b"foo", b"bar").unwrap();
+/// let t = TrieFactory::readonly(&memdb, &root).unwrap();
+/// let items: Vec<_> = t.iter().unwrap().collect::<Result<_>>().unwrap();
+/// assert_eq!(items, vec![(ccrypto::blake256(b"foo"), b"bar".to_vec())]);
+/// ```
+pub struct TrieIterator<'db> {
+    db: &'db dyn HashDB,
+    // The nodes which are not written to `db` yet.
+    overlay: MemoryDB,
+    root: H256,
+    trail: Vec<Crumb>,
+}
+
+impl<'db> TrieIterator<'db> {
+    /// Create a new iterator positioned at the first leaf of the trie with `root`.
+    pub fn new(db: &'db dyn HashDB, root: &H256) -> crate::Result<Self> {
+        Self::new_with_overlay(db, MemoryDB::new(), root)
+    }
+
+    /// Create a new iterator which reads the nodes in `overlay` before `db`.
+    pub(crate) fn new_with_overlay(db: &'db dyn HashDB, overlay: MemoryDB, root: &H256) -> crate::Result<Self> {
+        let mut iter = TrieIterator {
+            db,
+            overlay,
+            root: *root,
+            trail: Vec::new(),
+        };
+        if *root != BLAKE_NULL_RLP {
+            let node_rlp = iter.fetch(root)?;
+            iter.trail.push(Crumb {
+                node_rlp,
+                prefix: Vec::new(),
+                status: Status::Entering,
+            });
+        }
+        Ok(iter)
+    }
+
+    /// Position the iterator so that the next item is the first leaf
+    /// whose hashed key is greater than or equal to `key`.
+    pub fn seek(&mut self, key: &H256) -> crate::Result<()> {
+        self.trail.clear();
+        if self.root == BLAKE_NULL_RLP {
+            return Ok(())
+        }
+
+        let key = NibbleSlice::new(key).to_vec();
+        let mut hash = self.root;
+        let mut depth = 0;
+        loop {
+            let node_rlp = self.fetch(&hash)?;
+            let (status, next) = match RlpNode::decoded(&node_rlp) {
+                Some(RlpNode::Leaf(partial, _)) => {
+                    if partial.to_vec()[..] >= key[depth..] {
+                        (Status::Entering, None)
+                    } else {
+                        (Status::Exiting, None)
+                    }
+                }
+                Some(RlpNode::Branch(partial, children)) => {
+                    let end = std::cmp::min(depth + partial.len(), key.len());
+                    match partial.to_vec()[..].cmp(&key[depth..end]) {
+                        // Every leaf under this branch is smaller than the key.
+                        Ordering::Less => (Status::Exiting, None),
+                        Ordering::Equal if end < key.len() => {
+                            let index = key[end] as usize;
+                            (Status::AtChild(index + 1), children[index].map(|child| (child, end + 1)))
+                        }
+                        // Every leaf under this branch is greater than the key.
+                        _ => (Status::Entering, None),
+                    }
+                }
+                None => return Ok(()),
+            };
+            self.trail.push(Crumb {
+                node_rlp,
+                prefix: key[..depth].to_vec(),
+                status,
+            });
+
+            match next {
+                Some((child, child_depth)) => {
+                    hash = child;
+                    depth = child_depth;
+                }
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Yield the original keys looked up in `preimages` instead of the hashed keys.
+    pub fn with_preimages(self, preimages: &'db dyn HashDB) -> PreimageIterator<'db> {
+        self.with_preimage_store(Some(preimages))
+    }
+
+    /// Same as `with_preimages()`, but every leaf yields `MissingPreimage` if there is no store.
+    pub(crate) fn with_preimage_store(self, preimages: Option<&'db dyn HashDB>) -> PreimageIterator<'db> {
+        PreimageIterator {
+            inner: self,
+            preimages,
+        }
+    }
+
+    fn fetch(&self, hash: &H256) -> crate::Result<DBValue> {
+        self.overlay.get(hash).or_else(|| self.db.get(hash)).ok_or(TrieError::IncompleteDatabase(*hash))
+    }
+}
+
+impl<'db> Iterator for TrieIterator<'db> {
+    type Item = crate::Result<(H256, DBValue)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let step = {
+                let crumb = self.trail.last_mut()?;
+                match RlpNode::decoded(&crumb.node_rlp) {
+                    Some(RlpNode::Leaf(partial, value)) => {
+                        if crumb.status == Status::Entering {
+                            crumb.status = Status::Exiting;
+                            let mut path = crumb.prefix.clone();
+                            path.extend(partial.to_vec());
+                            Step::Yield(key_from_nibbles(&path), value.to_vec())
+                        } else {
+                            Step::Pop
+                        }
+                    }
+                    Some(RlpNode::Branch(partial, children)) => {
+                        let start = match crumb.status {
+                            Status::Entering => 0,
+                            Status::AtChild(index) => index,
+                            Status::Exiting => children.len(),
+                        };
+                        match children.iter().enumerate().skip(start).find_map(|(i, child)| child.map(|c| (i, c))) {
+                            Some((index, child)) => {
+                                crumb.status = Status::AtChild(index + 1);
+                                let mut path = crumb.prefix.clone();
+                                path.extend(partial.to_vec());
+                                path.push(index as u8);
+                                Step::Descend(child, path)
+                            }
+                            None => Step::Pop,
+                        }
+                    }
+                    None => Step::Pop,
+                }
+            };
+
+            match step {
+                Step::Yield(key, value) => return Some(Ok((key, value))),
+                Step::Descend(hash, prefix) => match self.fetch(&hash) {
+                    Ok(node_rlp) => self.trail.push(Crumb {
+                        node_rlp,
+                        prefix,
+                        status: Status::Entering,
+                    }),
+                    Err(err) => return Some(Err(err)),
+                },
+                Step::Pop => {
+                    self.trail.pop();
+                }
+            }
+        }
+    }
+}
+
+/// Iterator over the leaves of a trie which yields the original keys instead of the hashed keys.
+///
+/// The original keys are read from a preimage store filled by a trie created with
+/// `TrieFactory::create_with_preimages`. A leaf without a preimage yields `MissingPreimage`.
+pub struct PreimageIterator<'db> {
+    inner: TrieIterator<'db>,
+    preimages: Option<&'db dyn HashDB>,
+}
+
+impl<'db> PreimageIterator<'db> {
+    /// Position the iterator so that the next item is the first leaf
+    /// whose hashed key is greater than or equal to `key`.
+    pub fn seek(&mut self, key: &H256) -> crate::Result<()> {
+        self.inner.seek(key)
+    }
+}
+
+impl<'db> Iterator for PreimageIterator<'db> {
+    type Item = crate::Result<(Bytes, DBValue)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let preimages = self.preimages;
+        self.inner.next().map(|item| {
+            let (hash, value) = item?;
+            let key = preimages.and_then(|preimages| preimages.get(&hash)).ok_or(TrieError::MissingPreimage(hash))?;
+            Ok((key, value))
+        })
+    }
+}
+
+/// Pack the nibbles of a full path into the hashed key.
+pub(crate) fn key_from_nibbles(nibbles: &[u8]) -> H256 {
+    debug_assert_eq!(nibbles.len(), 64);
+    let mut key = H256::zero();
+    for (i, pair) in nibbles.chunks(2).take(32).enumerate() {
+        key[i] = (pair[0] << 4) | pair.get(1).cloned().unwrap_or(0);
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use ccrypto::blake256;
+    use cdb::MemoryDB;
+    use standardmap::{Alphabet, StandardMap, ValueMode};
+    use std::collections::BTreeMap;
+
+    fn populate(db: &mut MemoryDB, count: usize) -> (H256, BTreeMap<H256, DBValue>) {
+        let x = StandardMap {
+            alphabet: Alphabet::Custom(b"@QWERTYUIOPASDFGHJKLZXCVBNM[/]^_".to_vec()),
+            min_key: 5,
+            journal_key: 0,
+            value_mode: ValueMode::Index,
+            count,
+        }
+        .make_with(&mut H256::zero());
+
+        let mut root = H256::zero();
+        let mut expected = BTreeMap::new();
+        {
+            let mut t = TrieDBMut::new(db, &mut root);
+            for (key, value) in x {
+                t.insert(&key, &value).unwrap();
+                expected.insert(blake256(&key), value);
+            }
+        }
+        (root, expected)
+    }
+
+    #[test]
+    fn empty() {
+        let memdb = MemoryDB::new();
+        let t = TrieDB::try_new(&memdb, &BLAKE_NULL_RLP).unwrap();
+        assert_eq!(t.iter().unwrap().count(), 0);
+    }
+
+    #[test]
+    fn iterate_in_order() {
+        let mut memdb = MemoryDB::new();
+        let (root, expected) = populate(&mut memdb, 1000);
+
+        let t = TrieDB::try_new(&memdb, &root).unwrap();
+        let items: Vec<_> = t.iter().unwrap().collect::<Result<_>>().unwrap();
+        assert_eq!(items, expected.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn seek() {
+        let mut memdb = MemoryDB::new();
+        let (root, expected) = populate(&mut memdb, 1000);
+        let t = TrieDB::try_new(&memdb, &root).unwrap();
+
+        let mut targets = vec![H256::zero(), H256::from([0xff; 32])];
+        targets.extend(expected.keys().step_by(97).cloned());
+        targets.extend((0..100u64).map(|i| blake256(i.to_be_bytes())));
+
+        for target in targets {
+            let mut iter = t.iter().unwrap();
+            iter.seek(&target).unwrap();
+            let items: Vec<_> = iter.collect::<Result<_>>().unwrap();
+            let rest: Vec<_> = expected.range(target..).map(|(k, v)| (*k, v.clone())).collect();
+            assert_eq!(items, rest);
+        }
+    }
+
+    #[test]
+    fn iterate_original_keys() {
+        let x = StandardMap {
+            alphabet: Alphabet::Custom(b"@QWERTYUIOPASDFGHJKLZXCVBNM[/]^_".to_vec()),
+            min_key: 5,
+            journal_key: 0,
+            value_mode: ValueMode::Index,
+            count: 100,
+        }
+        .make_with(&mut H256::zero());
+
+        let mut memdb = MemoryDB::new();
+        let mut preimages = MemoryDB::new();
+        let mut root = H256::zero();
+        let mut expected = BTreeMap::new();
+        {
+            let mut t = TrieFactory::create_with_preimages(&mut memdb, &mut preimages, &mut root);
+            for (key, value) in x {
+                t.insert(&key, &value).unwrap();
+                expected.insert(blake256(&key), (key, value));
+            }
+        }
+
+        let t = TrieFactory::readonly_with_preimages(&memdb, &preimages, &root).unwrap();
+        for (hash, (key, _)) in &expected {
+            assert_eq!(t.get_preimage(hash).as_ref(), Some(key));
+        }
+        let items: Vec<_> = t.iter_with_preimages().unwrap().collect::<Result<_>>().unwrap();
+        assert_eq!(items, expected.values().cloned().collect::<Vec<_>>());
+        assert_eq!(t.iter().unwrap().with_preimages(&preimages).collect::<Result<Vec<_>>>().unwrap(), items);
+        drop(t);
+
+        // The uncommitted keys are iterated with the store of the trie as well.
+        let mut t = TrieFactory::from_existing_with_preimages(&mut memdb, &mut preimages, &mut root).unwrap();
+        t.insert(b"new key", b"new value").unwrap();
+        expected.insert(blake256(b"new key"), (b"new key".to_vec(), b"new value".to_vec()));
+        let items: Vec<_> = t.iter_with_preimages().unwrap().collect::<Result<_>>().unwrap();
+        assert_eq!(items, expected.values().cloned().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn missing_preimage() {
+        let mut memdb = MemoryDB::new();
+        let (root, expected) = populate(&mut memdb, 10);
+        let preimages = MemoryDB::new();
+
+        let t = TrieDB::try_new(&memdb, &root).unwrap();
+        let first = *expected.keys().next().unwrap();
+        assert_eq!(t.get_preimage(&first), None);
+        let mut iter = t.iter().unwrap().with_preimages(&preimages);
+        assert_eq!(iter.next(), Some(Err(TrieError::MissingPreimage(first))));
+        let mut iter = t.iter_with_preimages().unwrap();
+        assert_eq!(iter.next(), Some(Err(TrieError::MissingPreimage(first))));
+    }
+
+    #[test]
+    fn missing_node() {
+        let mut memdb = MemoryDB::new();
+        let (root, _) = populate(&mut memdb, 100);
+        let first_child = match RlpNode::decoded(&memdb.get(&root).unwrap()).unwrap() {
+            RlpNode::Branch(_, children) => children.iter().flatten().next().cloned().unwrap(),
+            RlpNode::Leaf(..) => unreachable!(),
+        };
+        memdb.remove(&first_child);
+
+        let t = TrieDB::try_new(&memdb, &root).unwrap();
+        assert!(t.iter().unwrap().any(|item| item == Err(TrieError::IncompleteDatabase(first_child))));
+    }
+}
@@ -17,23 +17,35 @@
 #[macro_use]
 extern crate rlp_derive;
 
+pub mod delta;
+pub mod diff;
+pub mod hasher;
+pub mod iterator;
 mod nibbleslice;
 pub mod node;
 pub mod proof;
+pub mod recorder;
+pub mod set;
 mod skewed;
 #[allow(dead_code)]
 pub mod snapshot;
+pub mod triebuilder;
 pub mod triedb;
 pub mod triedbmut;
 pub mod triehash;
 
+pub use crate::hasher::KeyHasher;
+pub use crate::iterator::{PreimageIterator, TrieIterator};
 pub use crate::node::Node;
+use crate::proof::CryptoStructure;
+pub use crate::recorder::Recorder;
 pub use crate::skewed::skewed_merkle_root;
-use crate::triedb::TrieDB;
-use crate::triedbmut::TrieDBMut;
+pub use crate::triebuilder::TrieBuilder;
+pub use crate::triedb::TrieDB;
+pub use crate::triedbmut::TrieDBMut;
 use ccrypto::BLAKE_NULL_RLP;
 use cdb::{DBValue, HashDB};
-use primitives::H256;
+use primitives::{Bytes, H256};
 use std::fmt;
 
 /// Trie Errors.
@@ -46,6 +58,15 @@ pub enum TrieError {
     InvalidStateRoot(H256),
     /// Trie item not found in the database,
     IncompleteDatabase(H256),
+    /// Original key of the hashed key not found in the preimage store.
+    MissingPreimage(H256),
+    /// The key given to `TrieBuilder` is not greater than the previous one.
+    UnsortedKey(H256),
+    /// The key hasher of the trie does not accept the key.
+    InvalidKey(Bytes),
+    /// A change of `TrieMut::apply_batch` failed with the first error, and undoing the applied changes failed with
+    /// the second one. Some of the changes are left applied.
+    FailedRollback(Box<TrieError>, Box<TrieError>),
 }
 
 impl fmt::Display for TrieError {
@@ -53,6 +74,15 @@ impl fmt::Display for TrieError {
         match self {
             TrieError::InvalidStateRoot(root) => write!(f, "Invalid state root: {}", root),
             TrieError::IncompleteDatabase(missing) => write!(f, "Database missing expected key: {}", missing),
+            TrieError::MissingPreimage(hash) => write!(f, "Preimage store missing the key of: {}", hash),
+            TrieError::UnsortedKey(key) => write!(f, "Key not in increasing order: {}", key),
+            TrieError::InvalidKey(key) => {
+                write!(f, "Key not accepted by the key hasher: 0x")?;
+                key.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+            }
+            TrieError::FailedRollback(err, rollback_err) => {
+                write!(f, "Failed to undo the changes after the error ({}): {}", err, rollback_err)
+            }
         }
     }
 }
@@ -77,10 +107,26 @@ pub trait Trie {
     /// What is the value of the given key in this trie?
     fn get(&self, key: &[u8]) -> Result<Option<DBValue>>;
 
+    /// What is the original key of the given hashed key?
+    /// Always `None` if the trie was opened without a preimage store.
+    fn get_preimage(&self, _hash: &H256) -> Option<Bytes> {
+        None
+    }
+
     /// Does all the nodes in this trie exist in the underlying database?
     fn is_complete(&self) -> bool;
 }
 
+/// A trie whose leaves can be iterated in the order of their hashed keys.
+pub trait IterableTrie: Trie {
+    /// Returns an iterator over the leaves of this trie, in the order of their hashed keys.
+    fn iter(&self) -> Result<TrieIterator<'_>>;
+
+    /// Same as `iter()`, but yields the original keys looked up in the preimage store of the trie.
+    /// Every leaf yields `MissingPreimage` if the trie was opened without a preimage store.
+    fn iter_with_preimages(&self) -> Result<PreimageIterator<'_>>;
+}
+
 /// A key-value datastore implemented as a database-backed modified Merkle tree.
 pub trait TrieMut: Trie {
     /// Insert a `key`/`value` pair into the trie. An empty value is equivalent to removing
@@ -90,23 +136,119 @@ pub trait TrieMut: Trie {
     /// Remove a `key` from the trie. Equivalent to making it equal to the empty
     /// value. Returns the old value associated with this key, if it existed.
     fn remove(&mut self, key: &[u8]) -> Result<Option<DBValue>>;
+
+    /// Update the value of `key` with `f`, which takes the old value and returns the new value.
+    /// `None` or an empty value removes `key`. Returns the old value associated with this key, if it existed.
+    ///
+    /// By default, the old value is read with `get` and the new one is written with `insert` or `remove`,
+    /// so the path to `key` is walked twice. Implementations may override it to walk the path only once.
+    fn update<F>(&mut self, key: &[u8], f: F) -> Result<Option<DBValue>>
+    where
+        Self: Sized,
+        F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>>, {
+        let old_value = self.get(key)?;
+        match f(old_value.as_ref().map(AsRef::as_ref)) {
+            Some(ref value) if !value.is_empty() => self.insert(key, value),
+            _ => self.remove(key),
+        }
+    }
+
+    /// Apply `changes`, where `None` or an empty value removes the key, at once. The changes are either all
+    /// applied or, on error other than `FailedRollback`, none of them. Returns the old values in the order of
+    /// `changes`, as if they were applied one by one.
+    ///
+    /// By default, the changes are applied one by one, and the applied ones are undone in the reverse order on error.
+    /// If undoing fails, the rest are still undone, and `FailedRollback` is returned.
+    fn apply_batch<I, K, V>(&mut self, changes: I) -> Result<Vec<Option<DBValue>>>
+    where
+        Self: Sized,
+        I: IntoIterator<Item = (K, Option<V>)>,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>, {
+        let changes: Vec<(K, Option<V>)> = changes.into_iter().collect();
+        let mut old_values = Vec::with_capacity(changes.len());
+        for (key, value) in &changes {
+            let result = match value {
+                Some(value) => self.insert(key.as_ref(), value.as_ref()),
+                None => self.remove(key.as_ref()),
+            };
+            match result {
+                Ok(old_value) => old_values.push(old_value),
+                Err(err) => {
+                    let mut rollback_err = None;
+                    for ((key, _), old_value) in changes.iter().zip(old_values).rev() {
+                        // An undo can read other nodes than the change did, so it can fail as well.
+                        let result = match old_value {
+                            Some(old_value) => self.insert(key.as_ref(), &old_value),
+                            None => self.remove(key.as_ref()),
+                        };
+                        if let Err(undo_err) = result {
+                            rollback_err.get_or_insert(undo_err);
+                        }
+                    }
+                    return match rollback_err {
+                        Some(rollback_err) => Err(TrieError::FailedRollback(Box::new(err), Box::new(rollback_err))),
+                        None => Err(err),
+                    }
+                }
+            }
+        }
+        Ok(old_values)
+    }
+
+    /// Write the changes to the underlying database. Does nothing if the changes are written as they are made.
+    fn commit(&mut self) {}
 }
 
 pub enum TrieFactory {}
 
 impl TrieFactory {
     /// Create new immutable instance of Trie.
-    pub fn readonly<'db>(db: &'db dyn HashDB, root: &'db H256) -> Result<impl Trie + 'db> {
+    pub fn readonly<'db>(db: &'db dyn HashDB, root: &'db H256) -> Result<impl IterableTrie + CryptoStructure + 'db> {
         Ok(TrieDB::try_new(db, root)?)
     }
 
     /// Create new mutable instance of Trie.
-    pub fn create<'db>(db: &'db mut dyn HashDB, root: &'db mut H256) -> impl TrieMut + 'db {
+    pub fn create<'db>(
+        db: &'db mut dyn HashDB,
+        root: &'db mut H256,
+    ) -> impl TrieMut + IterableTrie + CryptoStructure + 'db {
         TrieDBMut::new(db, root)
     }
 
     /// Create new mutable instance of trie and check for errors.
-    pub fn from_existing<'db>(db: &'db mut dyn HashDB, root: &'db mut H256) -> Result<impl TrieMut + 'db> {
+    pub fn from_existing<'db>(
+        db: &'db mut dyn HashDB,
+        root: &'db mut H256,
+    ) -> Result<impl TrieMut + IterableTrie + CryptoStructure + 'db> {
         Ok(TrieDBMut::from_existing(db, root)?)
     }
+
+    /// Create new immutable instance of Trie which looks up original keys in `preimages`.
+    pub fn readonly_with_preimages<'db>(
+        db: &'db dyn HashDB,
+        preimages: &'db dyn HashDB,
+        root: &'db H256,
+    ) -> Result<impl IterableTrie + CryptoStructure + 'db> {
+        Ok(TrieDB::try_new(db, root)?.with_preimages(preimages))
+    }
+
+    /// Create new mutable instance of Trie which records the original keys in `preimages`.
+    pub fn create_with_preimages<'db>(
+        db: &'db mut dyn HashDB,
+        preimages: &'db mut dyn HashDB,
+        root: &'db mut H256,
+    ) -> impl TrieMut + IterableTrie + CryptoStructure + 'db {
+        TrieDBMut::new(db, root).with_preimages(preimages)
+    }
+
+    /// Create new mutable instance of trie which records the original keys in `preimages`
+    /// and check for errors.
+    pub fn from_existing_with_preimages<'db>(
+        db: &'db mut dyn HashDB,
+        preimages: &'db mut dyn HashDB,
+        root: &'db mut H256,
+    ) -> Result<impl TrieMut + IterableTrie + CryptoStructure + 'db> {
+        Ok(TrieDBMut::from_existing(db, root)?.with_preimages(preimages))
+    }
 }
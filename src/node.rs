@@ -28,34 +28,52 @@ pub enum Node<'a> {
 
 impl<'a> Node<'a> {
     /// Decode the `node_rlp` and return the Node.
+    /// It panics if `node_rlp` is not a valid node. Use `try_decoded()` for untrusted data.
     pub fn decoded(node_rlp: &'a [u8]) -> Option<Self> {
+        Self::try_decoded(node_rlp).expect("Rlp data is not valid.")
+    }
+
+    /// Decode the `node_rlp` and return the Node, or an error if `node_rlp` is not a valid node.
+    /// It never panics, so it is safe to use with the nodes received from others.
+    pub fn try_decoded(node_rlp: &'a [u8]) -> Result<Option<Self>, DecoderError> {
         let r = Rlp::new(node_rlp);
-        match r.prototype().unwrap() {
+        if r.payload_info()?.total() != node_rlp.len() {
+            return Err(DecoderError::Custom("Trailing bytes after the node"))
+        }
+        match r.prototype()? {
             // Empty node
-            Prototype::Data(0) => None,
+            Prototype::Data(0) => Ok(None),
             // leaf node - first is nibbles and second is value
             Prototype::List(2) => {
-                let slice = NibbleSlice::from_encoded(r.at(0).unwrap().data().unwrap());
+                let slice = decode_partial(&r.at(0)?)?;
+                let value = r.at(1)?;
+                if !value.is_data() {
+                    return Err(DecoderError::Custom("Leaf value is not a byte string"))
+                }
 
-                Some(Node::Leaf(slice, r.at(1).unwrap().data().unwrap()))
+                Ok(Some(Node::Leaf(slice, value.data()?)))
             }
             // branch node - first is nibbles (or empty), the rest 16 are nodes.
             Prototype::List(17) => {
                 let mut nodes = [None; 16];
                 debug_assert_eq!(16, nodes.len());
                 for (i, node) in nodes.iter_mut().enumerate().map(|(i, node)| (i + 1, node)) {
-                    *node = if r.at(i).unwrap().is_empty() {
+                    let child = r.at(i)?;
+                    if !child.is_data() {
+                        return Err(DecoderError::Custom("Child of a branch is not a hash"))
+                    }
+                    *node = if child.is_empty() {
                         None
                     } else {
-                        Some(r.val_at::<H256>(i).unwrap())
+                        Some(child.as_val::<H256>()?)
                     };
                 }
 
-                Some(Node::Branch(NibbleSlice::from_encoded(r.at(0).unwrap().data().unwrap()), nodes.into()))
+                Ok(Some(Node::Branch(decode_partial(&r.at(0)?)?, nodes.into())))
             }
 
             // something went wrong.
-            _ => panic!("Rlp data is not valid."),
+            _ => Err(DecoderError::Custom("Rlp data is not a node")),
         }
     }
 
@@ -119,3 +137,17 @@ impl<'a> Node<'a> {
         }
     }
 }
+
+/// Decode the hex-prefix encoded partial path of a node.
+/// The first byte must be `0x00` for an even number of nibbles, or `0x1X` for an odd number of nibbles.
+fn decode_partial<'a>(r: &Rlp<'a>) -> Result<NibbleSlice<'a>, DecoderError> {
+    if !r.is_data() {
+        return Err(DecoderError::Custom("Partial path is not a byte string"))
+    }
+    let data = r.data()?;
+    match data.first() {
+        Some(0x00) => Ok(NibbleSlice::from_encoded(data)),
+        Some(flag) if flag & 0xf0 == 0x10 => Ok(NibbleSlice::from_encoded(data)),
+        _ => Err(DecoderError::Custom("Invalid partial path")),
+    }
+}
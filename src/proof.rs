@@ -14,14 +14,33 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use crate::iterator::key_from_nibbles;
 use crate::nibbleslice::NibbleSlice;
 use crate::node::Node;
 use ccrypto::{blake256, BLAKE_NULL_RLP};
+use cdb::{HashDB, MemoryDB};
 use primitives::Bytes;
 use primitives::H256;
+use rlp::{Decodable, DecoderError, Encodable, Rlp, RlpStream};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// The version of the RLP encoding of `CryptoProof` and `CryptoProofUnit`.
+///
+/// Version 2 changed the absence proofs of the keys which diverge from the partial path of a branch.
+/// Such a proof now ends with that branch, while version 1 left it out, so `verify` rejects the proofs of version 1.
+pub const PROOF_ENCODING_VERSION: u8 = 2;
+/// The maximum number of nodes a decoded `CryptoProof` can have.
+pub const MAX_PROOF_NODES: usize = 1 << 16;
+/// The maximum size of a node or a value in a decoded proof.
+pub const MAX_PROOF_NODE_SIZE: usize = 1 << 20;
+/// The maximum number of nodes in the proof of a single key.
+/// A key has 64 nibbles, so a path has at most 64 branches and a leaf.
+pub const MAX_PROOF_DEPTH: usize = 65;
+/// The number of nibbles in a key.
+const KEY_NIBBLES: usize = 64;
 
 // Unit of a proof.
-//#[derive(Clone, Eq, PartialEq, Debug, RlpEncodable, RlpDecodable)]
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub struct CryptoProofUnit {
     pub root: H256,
@@ -32,8 +51,174 @@ pub struct CryptoProofUnit {
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub struct CryptoProof(pub Vec<Bytes>);
 
+/// Rejects the bytes following the RLP item.
+fn check_no_trailing_bytes(rlp: &Rlp<'_>) -> Result<(), DecoderError> {
+    if rlp.payload_info()?.total() != rlp.as_raw().len() {
+        return Err(DecoderError::Custom("Trailing bytes after the proof"))
+    }
+    Ok(())
+}
+
+fn check_version(rlp: &Rlp<'_>) -> Result<(), DecoderError> {
+    if rlp.val_at::<u8>(0)? != PROOF_ENCODING_VERSION {
+        return Err(DecoderError::Custom("Unsupported proof encoding version"))
+    }
+    Ok(())
+}
+
+fn bounded_data<'a>(rlp: &Rlp<'a>) -> Result<&'a [u8], DecoderError> {
+    if !rlp.is_data() {
+        return Err(DecoderError::Custom("Proof item is not a byte string"))
+    }
+    let data = rlp.data()?;
+    if data.len() > MAX_PROOF_NODE_SIZE {
+        return Err(DecoderError::Custom("Proof item is too big"))
+    }
+    Ok(data)
+}
+
+/// Encoded as `[version, root, key, [value]]`, where the value list is empty in case of absence.
+impl Encodable for CryptoProofUnit {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        s.begin_list(4).append(&PROOF_ENCODING_VERSION).append(&self.root).append(&self.key);
+        match &self.value {
+            Some(value) => s.begin_list(1).append(value),
+            None => s.begin_list(0),
+        };
+    }
+}
+
+impl Decodable for CryptoProofUnit {
+    fn decode(rlp: &Rlp<'_>) -> Result<Self, DecoderError> {
+        check_no_trailing_bytes(rlp)?;
+        if rlp.item_count()? != 4 {
+            return Err(DecoderError::Custom("Invalid number of items in the proof unit"))
+        }
+        check_version(rlp)?;
+        let value_rlp = rlp.at(3)?;
+        let value = match value_rlp.item_count()? {
+            0 => None,
+            1 => Some(bounded_data(&value_rlp.at(0)?)?.to_vec()),
+            _ => return Err(DecoderError::Custom("More than one value in the proof unit")),
+        };
+        Ok(CryptoProofUnit {
+            root: rlp.val_at(1)?,
+            key: rlp.val_at(2)?,
+            value,
+        })
+    }
+}
+
+/// Encoded as `[version, [node, ...]]`.
+impl Encodable for CryptoProof {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        s.begin_list(2).append(&PROOF_ENCODING_VERSION).begin_list(self.0.len());
+        for node in &self.0 {
+            s.append(node);
+        }
+    }
+}
+
+impl Decodable for CryptoProof {
+    fn decode(rlp: &Rlp<'_>) -> Result<Self, DecoderError> {
+        check_no_trailing_bytes(rlp)?;
+        if rlp.item_count()? != 2 {
+            return Err(DecoderError::Custom("Invalid number of items in the proof"))
+        }
+        check_version(rlp)?;
+        let nodes_rlp = rlp.at(1)?;
+        if nodes_rlp.item_count()? > MAX_PROOF_NODES {
+            return Err(DecoderError::Custom("Too many proof nodes"))
+        }
+        let nodes = nodes_rlp.iter().map(|node| bounded_data(&node).map(<[u8]>::to_vec)).collect::<Result<_, _>>()?;
+        Ok(CryptoProof(nodes))
+    }
+}
+
+/// The tries which can prove their contents.
+/// The tries created by `TrieFactory` implement it, so the proofs of uncommitted state can be made as well.
+///
+/// ```
+/// use cdb::*;
+/// use merkle_trie::proof::{verify, CryptoStructure};
+/// use merkle_trie::*;
+/// use primitives::H256;
+///
+/// let mut memdb = MemoryDB::new();
+/// let mut root = H256::zero();
+/// let mut t = TrieFactory::create(&mut memdb, &mut root);
+/// t.insert(b"foo", b"bar").unwrap();
+/// let (unit, proof) = t.make_proof(&ccrypto::blake256(b"foo")).unwrap();
+/// assert_eq!(unit.value, Some(b"bar".to_vec()));
+/// assert!(verify(&proof, &unit));
+/// ```
 pub trait CryptoStructure {
     fn make_proof(&self, key: &H256) -> crate::Result<(CryptoProofUnit, CryptoProof)>;
+
+    /// Returns every leaf whose key is in `[start, end]`, in the order of the keys,
+    /// together with the nodes needed to show that no other leaf is in the range.
+    fn make_range_proof(&self, start: &H256, end: &H256) -> crate::Result<(Vec<(H256, Bytes)>, CryptoProof)>;
+
+    /// Makes the proofs of all `keys` at once.
+    /// A node shared by several proofs appears only once in the returned proof.
+    fn make_multi_proof(&self, keys: &[H256]) -> crate::Result<(Vec<CryptoProofUnit>, CryptoProof)> {
+        let mut units = Vec::with_capacity(keys.len());
+        let mut nodes = Vec::new();
+        let mut hashes = HashSet::new();
+        for key in keys {
+            let (unit, proof) = self.make_proof(key)?;
+            units.push(unit);
+            for node in proof.0 {
+                if hashes.insert(blake256(&node)) {
+                    nodes.push(node);
+                }
+            }
+        }
+        Ok((units, CryptoProof(nodes)))
+    }
+}
+
+/// Can a node under the nibble path `prefix` have a key in the range `[start, end]`?
+pub(crate) fn range_overlaps(prefix: &[u8], start: &[u8], end: &[u8]) -> bool {
+    let len = std::cmp::min(prefix.len(), start.len());
+    start[..len] <= prefix[..len] && prefix[..len] <= end[..len]
+}
+
+/// Why a proof failed the verification.
+/// The index is the position of the offending node in the proof.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ProofError {
+    /// The proof exceeds `MAX_PROOF_DEPTH` or `MAX_PROOF_NODE_SIZE`.
+    TooLarge,
+    /// step1: The proven value differs from the value of the unit.
+    ValueMismatch,
+    /// step2: The first node of the proof doesn't hash to the root.
+    RootMismatch,
+    /// step3: The node is not a valid RLP encoded node.
+    InvalidNode(usize),
+    /// step3: The node doesn't hash to the child hash in its parent.
+    HashMismatch(usize),
+    /// step3: The key diverges from the path of the node.
+    PathMismatch(usize),
+    /// step3: The path continues, but the proof has no node at the index.
+    Incomplete(usize),
+    /// step3: The path ends before the node at the index.
+    UnexpectedNode(usize),
+}
+
+impl fmt::Display for ProofError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProofError::TooLarge => write!(f, "Proof exceeds the size limits"),
+            ProofError::ValueMismatch => write!(f, "Proven value doesn't match the value of the unit"),
+            ProofError::RootMismatch => write!(f, "First proof node doesn't match the root"),
+            ProofError::InvalidNode(index) => write!(f, "Proof node {} is not a valid node", index),
+            ProofError::HashMismatch(index) => write!(f, "Proof node {} doesn't match the hash in its parent", index),
+            ProofError::PathMismatch(index) => write!(f, "Key diverges from the path of proof node {}", index),
+            ProofError::Incomplete(index) => write!(f, "Proof node {} is missing", index),
+            ProofError::UnexpectedNode(index) => write!(f, "Proof node {} follows the end of the path", index),
+        }
+    }
 }
 
 /// A verification logic of TrieDB's Merkle proof.
@@ -41,83 +226,254 @@ pub trait CryptoStructure {
 /// It verifies the proof with a given unit of test.
 /// It should never abort or fail, but only return 'false' as a result of getting an invalid or ill-formed proof.
 pub fn verify(proof: &CryptoProof, test: &CryptoProofUnit) -> bool {
+    verify_detailed(proof, test).is_ok()
+}
+
+/// The same verification as verify(), but it tells why the proof is rejected.
+pub fn verify_detailed(proof: &CryptoProof, test: &CryptoProofUnit) -> Result<(), ProofError> {
     // step1: verify the value
-    fn step1(proof: &CryptoProof, test: &CryptoProofUnit) -> bool {
-        match Node::decoded(&proof.0.last().unwrap()) {
-            Some(x) => match x {
-                Node::Leaf(_, value) => test.value.as_ref().unwrap() == &value,
-                _ => false,
-            },
-            _ => false,
-        }
-    };
-
-    // step2: verify the root
-    fn step2(proof: &CryptoProof, test: &CryptoProofUnit) -> bool {
-        blake256(&proof.0[0]) == test.root
-    };
-
-    // step3 (presence): verify the key
-    fn step3_p(proof: &CryptoProof, test: &CryptoProofUnit) -> bool {
-        fn verify_branch(path: &NibbleSlice<'_>, hash: &H256, proof: &[Bytes]) -> bool {
-            if *hash != blake256(&proof[0]) {
-                return false
+    fn step1(proof: &CryptoProof, test: &CryptoProofUnit) -> Result<(), ProofError> {
+        let index = proof.0.len() - 1;
+        match (Node::try_decoded(&proof.0[index]), &test.value) {
+            (Ok(Some(Node::Leaf(_, value))), Some(expected)) if expected.as_slice() == value => Ok(()),
+            (Ok(_), _) => Err(ProofError::ValueMismatch),
+            (Err(_), _) => Err(ProofError::InvalidNode(index)),
+        }
+    }
+
+    if let Some(result) = check_trivial(proof, &test.root) {
+        return result.and_then(|value| match (value, &test.value) {
+            (None, None) => Ok(()),
+            _ => Err(ProofError::ValueMismatch),
+        })
+    }
+    if test.value.is_some() {
+        step1(proof, test)?;
+        step2(proof, &test.root)?;
+        match step3(proof, &test.root, &test.key)? {
+            Some(_) => Ok(()),
+            // the key diverges from the path at the last node.
+            None => Err(ProofError::PathMismatch(proof.0.len() - 1)),
+        }
+    } else {
+        step2(proof, &test.root)?;
+        match step3(proof, &test.root, &test.key)? {
+            Some(_) => Err(ProofError::ValueMismatch),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Learns the value of `key` from the proof, instead of verifying a given value.
+/// It returns `Ok(None)` if the proof shows that the trie with `root` doesn't have `key`.
+pub fn extract(proof: &CryptoProof, root: &H256, key: &H256) -> Result<Option<Bytes>, ProofError> {
+    if let Some(result) = check_trivial(proof, root) {
+        return result
+    }
+    step2(proof, root)?;
+    Ok(step3(proof, root, key)?.map(<[u8]>::to_vec))
+}
+
+/// Handles the proof of an empty trie and the proofs exceeding the limits.
+fn check_trivial(proof: &CryptoProof, root: &H256) -> Option<Result<Option<Bytes>, ProofError>> {
+    if proof.0.is_empty() {
+        // special case of an empty trie.
+        if *root != BLAKE_NULL_RLP {
+            return Some(Err(ProofError::Incomplete(0)))
+        }
+        return Some(Ok(None))
+    }
+    if proof.0.len() > MAX_PROOF_DEPTH || !is_bounded(proof) {
+        return Some(Err(ProofError::TooLarge))
+    }
+    None
+}
+
+// step2: verify the root
+fn step2(proof: &CryptoProof, root: &H256) -> Result<(), ProofError> {
+    if blake256(&proof.0[0]) != *root {
+        return Err(ProofError::RootMismatch)
+    }
+    Ok(())
+}
+
+// step3: follow the key from the root, and return the value of the key or None in case of absence.
+// The path must end at the last node of the proof.
+fn step3<'a>(proof: &'a CryptoProof, root: &H256, key: &H256) -> Result<Option<&'a [u8]>, ProofError> {
+    fn walk<'a>(
+        path: &NibbleSlice<'_>,
+        hash: &H256,
+        proof: &'a [Bytes],
+        index: usize,
+    ) -> Result<Option<&'a [u8]>, ProofError> {
+        if *hash != blake256(&proof[0]) {
+            return Err(ProofError::HashMismatch(index))
+        }
+        let end_of_path = |value| {
+            if proof.len() > 1 {
+                return Err(ProofError::UnexpectedNode(index + 1))
             }
-            match Node::decoded(&proof[0]) {
-                Some(Node::Leaf(partial, _)) => path == &partial,
-                Some(Node::Branch(partial, table)) => {
-                    if proof.len() < 2 {
-                        // detect ill-formed proof
-                        return false
-                    }
-                    if !path.starts_with(&partial) {
-                        return false
+            Ok(value)
+        };
+        match Node::try_decoded(&proof[0]) {
+            Ok(Some(Node::Leaf(partial, value))) => {
+                if path.len() != partial.len() {
+                    return Err(ProofError::PathMismatch(index))
+                }
+                if path == &partial {
+                    end_of_path(Some(value))
+                } else {
+                    end_of_path(None) // special case : there is only one leaf node in the trie,
+                }
+            }
+            Ok(Some(Node::Branch(partial, children))) => {
+                if partial.len() >= path.len() {
+                    return Err(ProofError::PathMismatch(index))
+                }
+                if !path.starts_with(&partial) {
+                    return end_of_path(None)
+                }
+                match children[path.at(partial.len()) as usize] {
+                    Some(x) => {
+                        if proof.len() < 2 {
+                            // detect ill-formed proof
+                            return Err(ProofError::Incomplete(index + 1))
+                        }
+                        walk(&path.mid(partial.len() + 1), &x, &proof[1..], index + 1)
                     }
-                    match table[path.at(partial.len()) as usize] {
-                        Some(x) => verify_branch(&path.mid(partial.len() + 1), &x, &proof[1..]),
-                        None => false,
+                    None => end_of_path(None),
+                }
+            }
+            Ok(None) | Err(_) => Err(ProofError::InvalidNode(index)),
+        }
+    }
+    walk(&NibbleSlice::new(key), root, &proof.0, 0)
+}
+
+/// Make a node store which has only the nodes of `proofs`.
+///
+/// `TrieDB` and `TrieDBMut` opened on it with the proven root work on the paths covered by the proofs,
+/// and return `TrieError::IncompleteDatabase` outside of them.
+/// The nodes don't need to be verified beforehand, since a node can be reached only by its hash.
+pub fn proof_db<'a>(proofs: impl IntoIterator<Item = &'a CryptoProof>) -> MemoryDB {
+    let mut db = MemoryDB::new();
+    for proof in proofs {
+        for node_rlp in &proof.0 {
+            db.insert(node_rlp);
+        }
+    }
+    db
+}
+
+/// Does the proof fit in the limits of a decoded proof?
+fn is_bounded(proof: &CryptoProof) -> bool {
+    proof.0.len() <= MAX_PROOF_NODES && proof.0.iter().all(|node_rlp| node_rlp.len() <= MAX_PROOF_NODE_SIZE)
+}
+
+/// A verification logic of TrieDB's multi proof.
+/// For the format of proof, check the make_multi_proof() function.
+/// It rebuilds the proof of each unit from the shared nodes and verifies it as verify() does.
+/// The units can prove both presence and absence, and the nodes of the proof can be given in any order.
+/// Like verify(), it never panics on an ill-formed proof.
+pub fn verify_multi(proof: &CryptoProof, tests: &[CryptoProofUnit]) -> bool {
+    fn single_proof(nodes: &HashMap<H256, &Bytes>, test: &CryptoProofUnit) -> CryptoProof {
+        let mut single = Vec::new();
+        let mut path = NibbleSlice::new(&test.key);
+        let mut hash = test.root;
+        while let Some(node_rlp) = nodes.get(&hash) {
+            single.push(node_rlp.to_vec());
+            match Node::try_decoded(node_rlp) {
+                Ok(Some(Node::Branch(partial, children)))
+                    if path.starts_with(&partial) && partial.len() < path.len() =>
+                {
+                    match children[path.at(partial.len()) as usize] {
+                        Some(child) => {
+                            hash = child;
+                            path = path.mid(partial.len() + 1);
+                        }
+                        None => break,
                     }
                 }
-                None => false,
+                _ => break,
             }
+        }
+        CryptoProof(single)
+    }
+
+    if !is_bounded(proof) {
+        return false
+    }
+    let nodes: HashMap<H256, &Bytes> = proof.0.iter().map(|node_rlp| (blake256(node_rlp), node_rlp)).collect();
+    tests.iter().all(|test| verify(&single_proof(&nodes, test), test))
+}
+
+/// A verification logic of TrieDB's range proof.
+/// For the format of proof, check the make_range_proof() function.
+/// It returns true only if `items` are exactly the leaves whose keys are in `[start, end]`
+/// in the trie with `root`, so a proof with an omitted leaf is rejected.
+/// The nodes of the proof can be given in any order.
+/// Like verify(), it never panics on an ill-formed proof.
+pub fn verify_range(root: &H256, start: &H256, end: &H256, items: &[(H256, Bytes)], proof: &CryptoProof) -> bool {
+    fn collect_leaves(
+        nodes: &HashMap<H256, &Bytes>,
+        hash: &H256,
+        prefix: Vec<u8>,
+        range: (&[u8], &[u8]),
+        leaves: &mut Vec<(H256, Bytes)>,
+    ) -> bool {
+        let (start, end) = range;
+        let node_rlp = match nodes.get(hash) {
+            Some(node_rlp) => node_rlp,
+            None => return false, // every node which can have a key in the range must be in the proof
         };
-        verify_branch(&NibbleSlice::new(&test.key), &test.root, &proof.0)
-    };
-
-    // step3 (absence): verify the key.
-    fn step3_a(proof: &CryptoProof, test: &CryptoProofUnit) -> bool {
-        fn verify_branch(path: &NibbleSlice<'_>, hash: &H256, proof: &[Bytes]) -> bool {
-            if *hash != blake256(&proof[0]) {
-                return false
+        match Node::try_decoded(node_rlp) {
+            Ok(Some(Node::Leaf(partial, value))) => {
+                let mut path = prefix;
+                path.extend(partial.to_vec());
+                if path.len() != KEY_NIBBLES {
+                    return false
+                }
+                if start <= &path[..] && &path[..] <= end {
+                    leaves.push((key_from_nibbles(&path), value.to_vec()));
+                }
+                true
             }
-            match Node::decoded(&proof[0]) {
-                Some(Node::Leaf(partial, _)) => path != &partial, // special case : there is only one leaf node in the trie,
-                Some(Node::Branch(partial, children)) => {
-                    if !path.starts_with(&partial) {
-                        return false
-                    }
-                    match children[path.at(partial.len()) as usize] {
-                        Some(x) => proof.len() >= 2 && verify_branch(&path.mid(partial.len() + 1), &x, &proof[1..]),
-                        None => proof.len() == 1,
+            Ok(Some(Node::Branch(partial, children))) => {
+                let mut path = prefix;
+                path.extend(partial.to_vec());
+                if path.len() >= KEY_NIBBLES {
+                    return false
+                }
+                for (index, child) in children.iter().enumerate() {
+                    if let Some(child) = child {
+                        let mut child_prefix = path.clone();
+                        child_prefix.push(index as u8);
+                        if range_overlaps(&child_prefix, start, end)
+                            && !collect_leaves(nodes, child, child_prefix, range, leaves)
+                        {
+                            return false
+                        }
                     }
                 }
-                None => false,
+                true
             }
-        };
-        verify_branch(&NibbleSlice::new(&test.key), &test.root, &proof.0)
-    };
+            Ok(None) | Err(_) => false,
+        }
+    }
 
-    if proof.0.is_empty() {
-        return test.root == BLAKE_NULL_RLP && test.value.is_none() // special case of an empty trie.
+    if *root == BLAKE_NULL_RLP {
+        return items.is_empty() // special case of an empty trie.
     }
-    if test.value.is_some() {
-        step1(proof, test) && step2(proof, test) && step3_p(proof, test)
-    } else {
-        step2(proof, test) && step3_a(proof, test)
+    if !is_bounded(proof) {
+        return false
     }
+    let nodes: HashMap<H256, &Bytes> = proof.0.iter().map(|node_rlp| (blake256(node_rlp), node_rlp)).collect();
+    let start = NibbleSlice::new(start).to_vec();
+    let end = NibbleSlice::new(end).to_vec();
+    let mut leaves = Vec::new();
+    collect_leaves(&nodes, root, Vec::new(), (&start, &end), &mut leaves) && leaves.as_slice() == items
 }
 
-
 #[cfg(test)]
 mod tests {
     extern crate rand;
@@ -185,6 +541,7 @@ mod tests {
                 continue
             }
 
+            drop(mt);
             let t = TrieDB::try_new(&memdb, &root).unwrap();
 
             // Be careful: there are some case where the proof is not unique.
@@ -248,6 +605,7 @@ mod tests {
                 continue // skip this iteration
             }
 
+            drop(mt);
             let t = TrieDB::try_new(&memdb, &root).unwrap();
 
             simple_test(&t, &key1, Some(val1), &key1, true);
@@ -269,9 +627,719 @@ mod tests {
         }
     }
 
+    #[test]
+    fn encode_proof() {
+        let proof = CryptoProof(vec![vec![0x01, 0x02], b"abc".to_vec()]);
+        let encoded = rlp::encode(&proof);
+        assert_eq!(encoded, vec![0xc9, 0x02, 0xc7, 0x82, 0x01, 0x02, 0x83, 0x61, 0x62, 0x63]);
+        assert_eq!(rlp::decode::<CryptoProof>(&encoded), Ok(proof));
+
+        let empty = CryptoProof(Vec::new());
+        let encoded = rlp::encode(&empty);
+        assert_eq!(encoded, vec![0xc2, 0x02, 0xc0]);
+        assert_eq!(rlp::decode::<CryptoProof>(&encoded), Ok(empty));
+    }
+
+    #[test]
+    fn encode_proof_unit() {
+        let presence = CryptoProofUnit {
+            root: H256::from([0x11; 32]),
+            key: H256::from([0x22; 32]),
+            value: Some(b"abc".to_vec()),
+        };
+        let mut expected = vec![0xf8, 0x48, 0x02, 0xa0];
+        expected.extend(&[0x11; 32]);
+        expected.push(0xa0);
+        expected.extend(&[0x22; 32]);
+        expected.extend(&[0xc4, 0x83, 0x61, 0x62, 0x63]);
+        let encoded = rlp::encode(&presence);
+        assert_eq!(encoded, expected);
+        assert_eq!(rlp::decode::<CryptoProofUnit>(&encoded), Ok(presence));
+
+        let absence = CryptoProofUnit {
+            root: H256::from([0x11; 32]),
+            key: H256::from([0x22; 32]),
+            value: None,
+        };
+        let mut expected = vec![0xf8, 0x44, 0x02, 0xa0];
+        expected.extend(&[0x11; 32]);
+        expected.push(0xa0);
+        expected.extend(&[0x22; 32]);
+        expected.push(0xc0);
+        let encoded = rlp::encode(&absence);
+        assert_eq!(encoded, expected);
+        assert_eq!(rlp::decode::<CryptoProofUnit>(&encoded), Ok(absence));
+    }
+
+    #[test]
+    fn encode_generated_proof() {
+        let seed = [0u8; 32];
+        let mut rng: StdRng = rand::SeedableRng::from_seed(seed);
+        let mut memdb = MemoryDB::new();
+        let (root, entries) = range_test_trie(&mut memdb, &mut rng, 100);
+        let t = TrieDB::try_new(&memdb, &root).unwrap();
+
+        for key in &[entries[0].0, blake256("absent")] {
+            let (unit, proof) = t.make_proof(key).unwrap();
+            let unit = rlp::decode::<CryptoProofUnit>(&rlp::encode(&unit)).unwrap();
+            let proof = rlp::decode::<CryptoProof>(&rlp::encode(&proof)).unwrap();
+            assert!(verify(&proof, &unit));
+        }
+    }
+
+    fn to_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+    }
+
+    /// Renders the proofs of a fixed trie in the format of `tests/vectors/proofs.txt`.
+    fn proof_vectors() -> String {
+        let mut memdb = MemoryDB::new();
+        let mut root = H256::zero();
+        {
+            let mut t = TrieDBMut::new(&mut memdb, &mut root);
+            for i in 0..16 {
+                t.insert(format!("key{}", i).as_bytes(), format!("value{}", i).as_bytes()).unwrap();
+            }
+        }
+        let t = TrieDB::try_new(&memdb, &root).unwrap();
+
+        let mut vectors = String::from("# name key unit proof\n");
+        let present = (0..4).map(|i| format!("key{}", i));
+        let absent = (16..20).map(|i| format!("key{}", i));
+        for name in present.chain(absent) {
+            let key = blake256(&name);
+            let (unit, proof) = t.make_proof(&key).unwrap();
+            assert!(verify(&proof, &unit));
+            let line =
+                format!("{} {} {} {}\n", name, to_hex(&key), to_hex(&rlp::encode(&unit)), to_hex(&rlp::encode(&proof)));
+            vectors.push_str(&line);
+        }
+        vectors
+    }
+
+    /// Set `UPDATE_PROOF_VECTORS` to rewrite the vectors after an intended change of the proof format.
+    #[test]
+    fn proof_vectors_are_stable() {
+        let vectors = proof_vectors();
+        if std::env::var_os("UPDATE_PROOF_VECTORS").is_some() {
+            let path = std::path::Path::new(file!()).parent().unwrap().join("../tests/vectors/proofs.txt");
+            std::fs::write(path, &vectors).unwrap();
+        }
+        assert_eq!(vectors, include_str!("../tests/vectors/proofs.txt"));
+
+        for line in vectors.lines().skip(1) {
+            let fields: Vec<_> = line.split(' ').collect();
+            let unit: CryptoProofUnit = rlp::decode(&from_hex(fields[2])).unwrap();
+            let proof: CryptoProof = rlp::decode(&from_hex(fields[3])).unwrap();
+            assert_eq!(to_hex(&unit.key), fields[1]);
+            assert!(verify(&proof, &unit));
+        }
+    }
+
+    #[test]
+    fn decode_malformed_proof() {
+        // Trailing bytes
+        assert!(rlp::decode::<CryptoProof>(&[0xc2, 0x02, 0xc0, 0x00]).is_err());
+        // Unknown version
+        assert!(rlp::decode::<CryptoProof>(&[0xc2, 0x03, 0xc0]).is_err());
+        // Version 1
+        assert!(rlp::decode::<CryptoProof>(&[0xc2, 0x01, 0xc0]).is_err());
+        // Missing nodes
+        assert!(rlp::decode::<CryptoProof>(&[0xc1, 0x02]).is_err());
+        // A node must not be a list
+        assert!(rlp::decode::<CryptoProof>(&[0xc3, 0x02, 0xc1, 0xc0]).is_err());
+        // Not a list
+        assert!(rlp::decode::<CryptoProof>(&[0x83, 0x61, 0x62, 0x63]).is_err());
+
+        // An oversized node
+        let proof = CryptoProof(vec![vec![0; MAX_PROOF_NODE_SIZE + 1]]);
+        assert!(rlp::decode::<CryptoProof>(&rlp::encode(&proof)).is_err());
+        // Too many nodes
+        let proof = CryptoProof(vec![Vec::new(); MAX_PROOF_NODES + 1]);
+        assert!(rlp::decode::<CryptoProof>(&rlp::encode(&proof)).is_err());
+        let proof = CryptoProof(vec![Vec::new(); MAX_PROOF_NODES]);
+        assert!(rlp::decode::<CryptoProof>(&rlp::encode(&proof)).is_ok());
+    }
+
+    #[test]
+    fn decode_malformed_proof_unit() {
+        let unit = CryptoProofUnit {
+            root: H256::from([0x11; 32]),
+            key: H256::from([0x22; 32]),
+            value: Some(b"abc".to_vec()),
+        };
+        let encoded = rlp::encode(&unit);
+
+        // Trailing bytes
+        let mut trailing = encoded.clone();
+        trailing.push(0x00);
+        assert!(rlp::decode::<CryptoProofUnit>(&trailing).is_err());
+        // Unknown version
+        let mut version = encoded.clone();
+        version[2] = 0x03;
+        assert!(rlp::decode::<CryptoProofUnit>(&version).is_err());
+        // Version 1
+        version[2] = 0x01;
+        assert!(rlp::decode::<CryptoProofUnit>(&version).is_err());
+        // Short key
+        let mut short_key = encoded.clone();
+        short_key[1] -= 1;
+        short_key[36] = 0x9f;
+        short_key.remove(37);
+        assert!(rlp::decode::<CryptoProofUnit>(&short_key).is_err());
+        // Two values
+        let mut two_values = encoded[..encoded.len() - 5].to_vec();
+        two_values[1] += 4;
+        two_values.extend(&[0xc8, 0x83, 0x61, 0x62, 0x63, 0x83, 0x61, 0x62, 0x63]);
+        assert!(rlp::decode::<CryptoProofUnit>(&two_values).is_err());
+
+        // An oversized value
+        let oversized = CryptoProofUnit {
+            value: Some(vec![0; MAX_PROOF_NODE_SIZE + 1]),
+            ..unit
+        };
+        assert!(rlp::decode::<CryptoProofUnit>(&rlp::encode(&oversized)).is_err());
+    }
+
+    #[test]
+    fn multi_proof() {
+        let seed = [0u8; 32];
+        let mut rng: StdRng = rand::SeedableRng::from_seed(seed);
+
+        for size in &[0, 1, 2, 234] {
+            let mut memdb = MemoryDB::new();
+            let (root, entries) = range_test_trie(&mut memdb, &mut rng, *size);
+            let t = TrieDB::try_new(&memdb, &root).unwrap();
+
+            // Mix the present keys and the absent keys.
+            let mut keys: Vec<H256> = entries.iter().take(50).map(|(key, _)| *key).collect();
+            for _ in 0..50 {
+                keys.push(blake256(format!("{}", rng.gen::<u64>())));
+            }
+
+            let (units, proof) = t.make_multi_proof(&keys).unwrap();
+            assert_eq!(units.len(), keys.len());
+            assert!(verify_multi(&proof, &units));
+
+            let mut total = 0;
+            for (unit, key) in units.iter().zip(keys.iter()) {
+                let (single_unit, single_proof) = t.make_proof(key).unwrap();
+                assert_eq!(unit, &single_unit);
+                total += single_proof.0.len();
+            }
+            assert!(proof.0.len() <= total);
+            if *size > 2 {
+                assert!(proof.0.len() < total);
+            }
+
+            // The order of the nodes doesn't matter.
+            let mut reversed = proof.clone();
+            reversed.0.reverse();
+            assert!(verify_multi(&reversed, &units));
+        }
+    }
+
+    #[test]
+    fn multi_proof_malicious() {
+        let seed = [0u8; 32];
+        let mut rng: StdRng = rand::SeedableRng::from_seed(seed);
+        let mut memdb = MemoryDB::new();
+        let (root, entries) = range_test_trie(&mut memdb, &mut rng, 100);
+        let t = TrieDB::try_new(&memdb, &root).unwrap();
+
+        let mut keys: Vec<H256> = entries.iter().step_by(10).map(|(key, _)| *key).collect();
+        keys.push(blake256("absent"));
+        let (units, proof) = t.make_multi_proof(&keys).unwrap();
+        assert!(verify_multi(&proof, &units));
+
+        // A modified value
+        let mut modified = units.clone();
+        modified[0].value = Some(b"modified".to_vec());
+        assert!(!verify_multi(&proof, &modified));
+
+        // A present key claimed to be absent
+        let mut absent = units.clone();
+        absent[1].value = None;
+        assert!(!verify_multi(&proof, &absent));
+
+        // An absent key claimed to be present
+        let mut present = units.clone();
+        present.last_mut().unwrap().value = Some(b"value".to_vec());
+        assert!(!verify_multi(&proof, &present));
+
+        // A missing node
+        for i in 0..proof.0.len() {
+            let mut missing = proof.clone();
+            missing.0.remove(i);
+            assert!(!verify_multi(&missing, &units));
+        }
+    }
+
+    fn range_test_trie(memdb: &mut MemoryDB, rng: &mut StdRng, size: usize) -> (H256, Vec<(H256, Bytes)>) {
+        let mut root = H256::zero();
+        let mut entries = Vec::new();
+        {
+            let mut mt = TrieDBMut::new(memdb, &mut root);
+            for _ in 0..size {
+                let k = format!("{}", rng.gen::<u64>());
+                let v = format!("{}", rng.gen::<u64>());
+                mt.insert(k.as_bytes(), v.as_bytes()).unwrap();
+                entries.push((blake256(&k), v.into_bytes()));
+            }
+        }
+        entries.sort();
+        entries.dedup_by(|a, b| a.0 == b.0);
+        (root, entries)
+    }
+
+    #[test]
+    fn range_proof() {
+        let seed = [0u8; 32];
+        let mut rng: StdRng = rand::SeedableRng::from_seed(seed);
+
+        for size in &[0, 1, 2, 10, 234] {
+            let mut memdb = MemoryDB::new();
+            let (root, entries) = range_test_trie(&mut memdb, &mut rng, *size);
+            let t = TrieDB::try_new(&memdb, &root).unwrap();
+
+            for _ in 0..20 {
+                let mut bounds = [H256::from(rng.gen::<[u8; 32]>()), H256::from(rng.gen::<[u8; 32]>())];
+                bounds.sort();
+                let [start, end] = bounds;
+
+                let (items, proof) = t.make_range_proof(&start, &end).unwrap();
+                let expected: Vec<_> = entries.iter().filter(|(k, _)| start <= *k && *k <= end).cloned().collect();
+                assert_eq!(items, expected);
+                assert!(verify_range(&root, &start, &end, &items, &proof));
+                // The proof doesn't prove other ranges.
+                if items.len() != entries.len() {
+                    assert!(!verify_range(&root, &H256::zero(), &H256::from([0xff; 32]), &items, &proof));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn range_proof_boundaries() {
+        let seed = [0u8; 32];
+        let mut rng: StdRng = rand::SeedableRng::from_seed(seed);
+        let mut memdb = MemoryDB::new();
+        let (root, entries) = range_test_trie(&mut memdb, &mut rng, 100);
+        let t = TrieDB::try_new(&memdb, &root).unwrap();
+
+        // Both boundaries are keys in the trie.
+        let (start, end) = (entries[10].0, entries[20].0);
+        let (items, proof) = t.make_range_proof(&start, &end).unwrap();
+        assert_eq!(items, entries[10..=20].to_vec());
+        assert!(verify_range(&root, &start, &end, &items, &proof));
+
+        // A single key.
+        let (items, proof) = t.make_range_proof(&start, &start).unwrap();
+        assert_eq!(items, entries[10..=10].to_vec());
+        assert!(verify_range(&root, &start, &start, &items, &proof));
+
+        // The whole trie.
+        let (start, end) = (H256::zero(), H256::from([0xff; 32]));
+        let (items, proof) = t.make_range_proof(&start, &end).unwrap();
+        assert_eq!(items, entries);
+        assert!(verify_range(&root, &start, &end, &items, &proof));
+
+        // An empty range.
+        let (items, proof) = t.make_range_proof(&end, &start).unwrap();
+        assert!(items.is_empty());
+        assert!(verify_range(&root, &end, &start, &items, &proof));
+    }
+
+    #[test]
+    fn range_proof_incomplete() {
+        let seed = [0u8; 32];
+        let mut rng: StdRng = rand::SeedableRng::from_seed(seed);
+        let mut memdb = MemoryDB::new();
+        let (root, entries) = range_test_trie(&mut memdb, &mut rng, 100);
+        let t = TrieDB::try_new(&memdb, &root).unwrap();
+
+        let (start, end) = (entries[10].0, entries[20].0);
+        let (items, proof) = t.make_range_proof(&start, &end).unwrap();
+
+        // An omitted leaf
+        let mut omitted = items.clone();
+        omitted.remove(5);
+        assert!(!verify_range(&root, &start, &end, &omitted, &proof));
+        let mut without_leaf = proof.clone();
+        without_leaf.0.retain(|node| match Node::decoded(node) {
+            Some(Node::Leaf(_, value)) => value != &items[5].1[..],
+            _ => true,
+        });
+        assert!(!verify_range(&root, &start, &end, &omitted, &without_leaf));
+
+        // A modified value
+        let mut modified = items.clone();
+        modified[3].1 = b"modified".to_vec();
+        assert!(!verify_range(&root, &start, &end, &modified, &proof));
+
+        // An extra leaf outside of the range
+        let mut extra = items.clone();
+        extra.push(entries[21].clone());
+        assert!(!verify_range(&root, &start, &end, &extra, &proof));
+
+        // A missing node
+        for i in 0..proof.0.len() {
+            let mut missing = proof.clone();
+            missing.0.remove(i);
+            assert!(!verify_range(&root, &start, &end, &items, &missing));
+        }
+    }
+
+    fn branch(partial: NibbleSlice<'_>, index: usize, child: &[u8]) -> Bytes {
+        let mut children = [None; 16];
+        children[index] = Some(blake256(child));
+        Node::encoded(Node::Branch(partial, Box::new(children)))
+    }
+
+    fn leaf(partial: NibbleSlice<'_>, value: &[u8]) -> Bytes {
+        Node::encoded(Node::Leaf(partial, value))
+    }
+
+    fn proof_unit(proof: &[Bytes], key: H256, value: Option<&[u8]>) -> CryptoProofUnit {
+        CryptoProofUnit {
+            root: blake256(&proof[0]),
+            key,
+            value: value.map(<[u8]>::to_vec),
+        }
+    }
+
     // proof is created manually here
     #[test]
     fn some_malicious() {
-        // TODO
+        let key = H256::from([0x12; 32]);
+        let whole = NibbleSlice::new(&key);
+        let rest = whole.mid(1);
+        let empty = NibbleSlice::new(&[]);
+
+        // A valid proof
+        let proof = vec![branch(empty, 1, &leaf(rest, b"value")), leaf(rest, b"value")];
+        assert!(verify(&CryptoProof(proof.clone()), &proof_unit(&proof, key, Some(b"value"))));
+
+        // A forged leaf after the real one
+        let mut forged = proof.clone();
+        forged.push(leaf(rest, b"forged"));
+        assert!(!verify(&CryptoProof(forged.clone()), &proof_unit(&forged, key, Some(b"forged"))));
+        assert!(!verify(&CryptoProof(forged.clone()), &proof_unit(&forged, key, Some(b"value"))));
+
+        // A leaf whose partial is longer than the key
+        let long = vec![branch(empty, 1, &leaf(whole, b"value")), leaf(whole, b"value")];
+        assert!(!verify(&CryptoProof(long.clone()), &proof_unit(&long, key, Some(b"value"))));
+        assert!(!verify(&CryptoProof(long.clone()), &proof_unit(&long, key, None)));
+
+        // A branch whose partial covers the whole key
+        let full = vec![branch(whole, 0, &leaf(empty, b"value")), leaf(empty, b"value")];
+        assert!(!verify(&CryptoProof(full.clone()), &proof_unit(&full, key, Some(b"value"))));
+        assert!(!verify(&CryptoProof(full.clone()), &proof_unit(&full, key, None)));
+
+        // A chain of branches deeper than the key
+        let mut deep = vec![leaf(empty, b"value")];
+        for _ in 0..70 {
+            let parent = branch(empty, 0, &deep[0]);
+            deep.insert(0, parent);
+        }
+        let zero = H256::zero();
+        assert!(!verify(&CryptoProof(deep.clone()), &proof_unit(&deep, zero, Some(b"value"))));
+        assert!(!verify(&CryptoProof(deep.clone()), &proof_unit(&deep, zero, None)));
+        assert!(!verify_multi(&CryptoProof(deep.clone()), &[proof_unit(&deep, zero, Some(b"value"))]));
+        assert!(!verify_range(&blake256(&deep[0]), &zero, &zero, &[], &CryptoProof(deep.clone())));
+
+        // An oversized node
+        let value = vec![0; MAX_PROOF_NODE_SIZE];
+        let oversized = vec![leaf(whole, &value)];
+        assert!(!verify(&CryptoProof(oversized.clone()), &proof_unit(&oversized, key, Some(&value))));
+
+        // Ill-formed nodes
+        let mut short_hash = vec![0xd1, 0x80, 0x8f];
+        short_hash.extend(&[0; 15]);
+        short_hash.extend(&[0x80; 15]);
+        let ill_formed: Vec<Bytes> = vec![
+            vec![],
+            vec![0xc0],
+            vec![0xff],
+            vec![0xb8],
+            vec![0xc3, 0x80, 0x80],
+            vec![0xc2, 0x80, 0x80],
+            vec![0xc2, 0x00, 0xc0],
+            vec![0xc2, 0x20, 0x80],
+            vec![0xc3, 0x81, 0x30, 0x80],
+            vec![0xc3, 0xc1, 0x00, 0x80],
+            vec![0xc2, 0x00, 0x80, 0x00],
+            short_hash,
+        ];
+        for node in ill_formed {
+            assert!(Node::try_decoded(&node).is_err());
+            let nodes = vec![node.clone(), leaf(rest, b"value")];
+            for value in &[Some(&b"value"[..]), None] {
+                let unit = proof_unit(&nodes, key, *value);
+                assert!(!verify(&CryptoProof(vec![node.clone()]), &unit));
+                assert!(!verify(&CryptoProof(nodes.clone()), &unit));
+                assert!(!verify_multi(&CryptoProof(nodes.clone()), &[unit]));
+            }
+            assert!(!verify_range(&blake256(&node), &zero, &key, &[], &CryptoProof(nodes.clone())));
+        }
+    }
+
+    #[test]
+    fn detailed_errors() {
+        let key = H256::from([0x12; 32]);
+        let other = H256::from([0x13; 32]);
+        let whole = NibbleSlice::new(&key);
+        let rest = whole.mid(1);
+        let empty = NibbleSlice::new(&[]);
+        let proof = vec![branch(empty, 1, &leaf(rest, b"value")), leaf(rest, b"value")];
+        let unit = proof_unit(&proof, key, Some(b"value"));
+        assert_eq!(verify_detailed(&CryptoProof(proof.clone()), &unit), Ok(()));
+
+        // step1
+        let wrong_value = proof_unit(&proof, key, Some(b"wrong"));
+        assert_eq!(verify_detailed(&CryptoProof(proof.clone()), &wrong_value), Err(ProofError::ValueMismatch));
+        assert_eq!(
+            verify_detailed(&CryptoProof(proof.clone()), &proof_unit(&proof, key, None)),
+            Err(ProofError::ValueMismatch)
+        );
+        let mut invalid_leaf = proof.clone();
+        invalid_leaf[1] = vec![0xc0];
+        assert_eq!(verify_detailed(&CryptoProof(invalid_leaf), &unit), Err(ProofError::InvalidNode(1)));
+
+        // step2
+        let wrong_root = CryptoProofUnit {
+            root: blake256("wrong"),
+            ..unit.clone()
+        };
+        assert_eq!(verify_detailed(&CryptoProof(proof.clone()), &wrong_root), Err(ProofError::RootMismatch));
+        assert_eq!(verify_detailed(&CryptoProof(Vec::new()), &unit), Err(ProofError::Incomplete(0)));
+
+        // step3
+        let mut wrong_leaf = proof.clone();
+        wrong_leaf[1] = leaf(rest, b"wrong");
+        let wrong_leaf_unit = proof_unit(&wrong_leaf, key, Some(b"wrong"));
+        assert_eq!(verify_detailed(&CryptoProof(wrong_leaf), &wrong_leaf_unit), Err(ProofError::HashMismatch(1)));
+
+        let other_unit = proof_unit(&proof, other, Some(b"value"));
+        assert_eq!(verify_detailed(&CryptoProof(proof.clone()), &other_unit), Err(ProofError::PathMismatch(1)));
+
+        let root_only = vec![proof[0].clone()];
+        assert_eq!(
+            verify_detailed(&CryptoProof(root_only.clone()), &proof_unit(&root_only, key, None)),
+            Err(ProofError::Incomplete(1))
+        );
+
+        let mut trailing = proof.clone();
+        trailing.push(leaf(rest, b"value"));
+        let trailing_unit = proof_unit(&trailing, key, Some(b"value"));
+        assert_eq!(verify_detailed(&CryptoProof(trailing), &trailing_unit), Err(ProofError::UnexpectedNode(2)));
+
+        let absent = H256::from([0x22; 32]);
+        let mut absence = proof.clone();
+        absence.truncate(1);
+        assert_eq!(verify_detailed(&CryptoProof(absence.clone()), &proof_unit(&absence, absent, None)), Ok(()));
+        absence.push(leaf(rest, b"value"));
+        assert_eq!(
+            verify_detailed(&CryptoProof(absence.clone()), &proof_unit(&absence, absent, None)),
+            Err(ProofError::UnexpectedNode(1))
+        );
+
+        let mut invalid_root = vec![vec![0xc2, 0x20, 0x80]];
+        assert_eq!(
+            verify_detailed(&CryptoProof(invalid_root.clone()), &proof_unit(&invalid_root, key, None)),
+            Err(ProofError::InvalidNode(0))
+        );
+
+        // limits
+        invalid_root.resize(MAX_PROOF_DEPTH + 1, Vec::new());
+        assert_eq!(
+            verify_detailed(&CryptoProof(invalid_root.clone()), &proof_unit(&invalid_root, key, None)),
+            Err(ProofError::TooLarge)
+        );
+    }
+
+    #[test]
+    fn extract_value() {
+        let seed = [0u8; 32];
+        let mut rng: StdRng = rand::SeedableRng::from_seed(seed);
+
+        for size in &[0, 1, 2, 234] {
+            let mut memdb = MemoryDB::new();
+            let (root, entries) = range_test_trie(&mut memdb, &mut rng, *size);
+            let t = TrieDB::try_new(&memdb, &root).unwrap();
+
+            for (key, value) in entries.iter().take(20) {
+                let (_, proof) = t.make_proof(key).unwrap();
+                assert_eq!(extract(&proof, &root, key), Ok(Some(value.clone())));
+            }
+            for _ in 0..20 {
+                let key = blake256(format!("{}", rng.gen::<u64>()));
+                let (_, proof) = t.make_proof(&key).unwrap();
+                assert_eq!(extract(&proof, &root, &key), Ok(None));
+            }
+        }
+    }
+
+    #[test]
+    fn extract_diverging_branch() {
+        let key = H256::from([0x12; 32]);
+        let other = H256::from([0x34; 32]);
+        let child = leaf(NibbleSlice::new(&other).mid(3), b"value");
+        // The root branch has the partial path of 3'4, and the key starts with 1'2.
+        let root_partial = NibbleSlice::new(&other[..1]);
+        let proof = vec![branch(root_partial, 3, &child), child.clone()];
+        let root = blake256(&proof[0]);
+        assert_eq!(extract(&CryptoProof(proof[..1].to_vec()), &root, &key), Ok(None));
+        assert!(verify(&CryptoProof(proof[..1].to_vec()), &proof_unit(&proof, key, None)));
+        // The trailing node is not a part of the path.
+        assert_eq!(extract(&CryptoProof(proof.clone()), &root, &key), Err(ProofError::UnexpectedNode(1)));
+        assert_eq!(extract(&CryptoProof(proof.clone()), &root, &other), Ok(Some(b"value".to_vec())));
+    }
+
+    #[test]
+    fn absence_proof_ends_with_diverging_branch() {
+        let mut memdb = MemoryDB::new();
+        let mut builder = TrieBuilder::new(&mut memdb);
+        // The child 5 of the root is a branch with the partial path of 6.
+        let mut keys = vec![H256::zero(), H256::from([0x56; 32]), H256::from([0x56; 32])];
+        keys[1][1] = 0x00;
+        keys[2][1] = 0x11;
+        for key in &keys {
+            builder.insert(key, b"value").unwrap();
+        }
+        let root = builder.finish();
+        let t = TrieDB::try_new(&memdb, &root).unwrap();
+
+        // 0x58.. diverges from the partial path of the child 5.
+        let key = H256::from([0x58; 32]);
+        let (unit, proof) = t.make_proof(&key).unwrap();
+        assert_eq!(unit.value, None);
+        assert_eq!(proof.0.len(), 2);
+        match Node::decoded(&proof.0[1]) {
+            Some(Node::Branch(partial, _)) => assert_eq!(partial.to_vec(), vec![6]),
+            _ => panic!("The proof must end with the diverging branch"),
+        }
+        assert_eq!(verify_detailed(&proof, &unit), Ok(()));
+
+        // Version 1 left the diverging branch out.
+        let old = CryptoProof(proof.0[..1].to_vec());
+        assert_eq!(verify_detailed(&old, &unit), Err(ProofError::Incomplete(1)));
+    }
+
+    #[test]
+    fn extract_malicious() {
+        let key = H256::from([0x12; 32]);
+        let rest = NibbleSlice::new(&key).mid(1);
+        let empty = NibbleSlice::new(&[]);
+        let proof = vec![branch(empty, 1, &leaf(rest, b"value")), leaf(rest, b"value")];
+        let root = blake256(&proof[0]);
+        assert_eq!(extract(&CryptoProof(proof.clone()), &root, &key), Ok(Some(b"value".to_vec())));
+
+        assert_eq!(extract(&CryptoProof(proof.clone()), &blake256("wrong"), &key), Err(ProofError::RootMismatch));
+        assert_eq!(extract(&CryptoProof(proof[..1].to_vec()), &root, &key), Err(ProofError::Incomplete(1)));
+        assert_eq!(extract(&CryptoProof(Vec::new()), &root, &key), Err(ProofError::Incomplete(0)));
+        assert_eq!(extract(&CryptoProof(Vec::new()), &BLAKE_NULL_RLP, &key), Ok(None));
+
+        let mut forged = proof.clone();
+        forged.push(leaf(rest, b"forged"));
+        assert_eq!(extract(&CryptoProof(forged), &root, &key), Err(ProofError::UnexpectedNode(2)));
+
+        let mut wrong_leaf = proof;
+        wrong_leaf[1] = leaf(rest, b"forged");
+        assert_eq!(extract(&CryptoProof(wrong_leaf), &root, &key), Err(ProofError::HashMismatch(1)));
+    }
+
+    #[test]
+    fn stateless_trie() {
+        let seed = [0u8; 32];
+        let mut rng: StdRng = rand::SeedableRng::from_seed(seed);
+        let mut full_db = MemoryDB::new();
+        let mut full_root = H256::zero();
+        let mut keys = Vec::new();
+        {
+            let mut t = TrieDBMut::new(&mut full_db, &mut full_root);
+            for i in 0..200 {
+                let k = format!("{}", rng.gen::<u64>());
+                t.insert(k.as_bytes(), format!("value{}", i).as_bytes()).unwrap();
+                keys.push(k);
+            }
+        }
+        let absent: Vec<String> = (0..10).map(|i| format!("absent{}", i)).collect();
+
+        let proven: Vec<_> = keys.iter().take(20).chain(absent.iter()).collect();
+        let hashed: Vec<_> = proven.iter().map(blake256).collect();
+        let (_, proof) = TrieDB::try_new(&full_db, &full_root).unwrap().make_multi_proof(&hashed).unwrap();
+
+        let mut db = proof_db(&[proof]);
+        let mut root = full_root;
+        {
+            let t = TrieDB::try_new(&db, &root).unwrap();
+            let full = TrieDB::try_new(&full_db, &full_root).unwrap();
+            for key in &proven {
+                assert_eq!(t.get(key.as_bytes()), full.get(key.as_bytes()));
+            }
+            // Outside of the proven paths, it never answers differently from the full trie.
+            for key in keys.iter().skip(20) {
+                match t.get(key.as_bytes()) {
+                    Err(TrieError::IncompleteDatabase(_)) => {}
+                    result => assert_eq!(result, full.get(key.as_bytes())),
+                }
+            }
+        }
+
+        let mut full = TrieDBMut::from_existing(&mut full_db, &mut full_root).unwrap();
+        let mut t = TrieDBMut::from_existing(&mut db, &mut root).unwrap();
+        for (i, key) in proven.iter().enumerate() {
+            let value = format!("new value{}", i);
+            assert_eq!(t.insert(key.as_bytes(), value.as_bytes()), full.insert(key.as_bytes(), value.as_bytes()));
+            assert_eq!(t.root(), full.root());
+        }
+        // Removing a key may need the sibling of its leaf, which can be out of the proof.
+        for key in &proven {
+            match t.remove(key.as_bytes()) {
+                Err(TrieError::IncompleteDatabase(_)) => {}
+                result => {
+                    assert_eq!(result, full.remove(key.as_bytes()));
+                    assert_eq!(t.root(), full.root());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mutated_proofs_never_panic() {
+        let seed = [0u8; 32];
+        let mut rng: StdRng = rand::SeedableRng::from_seed(seed);
+        let mut memdb = MemoryDB::new();
+        let (root, entries) = range_test_trie(&mut memdb, &mut rng, 100);
+        let t = TrieDB::try_new(&memdb, &root).unwrap();
+
+        let (unit, proof) = t.make_proof(&entries[0].0).unwrap();
+        let (items, range_proof) = t.make_range_proof(&entries[0].0, &entries[10].0).unwrap();
+        for _ in 0..1000 {
+            let mut mutated = proof.clone();
+            let mut mutated_range = range_proof.clone();
+            for nodes in &mut [&mut mutated, &mut mutated_range] {
+                let node = rng.gen_range(0, nodes.0.len());
+                let index = rng.gen_range(0, nodes.0[node].len());
+                match rng.gen_range(0, 3) {
+                    0 => nodes.0[node][index] = rng.gen(),
+                    1 => nodes.0[node].truncate(index),
+                    _ => nodes.0[node].insert(index, rng.gen()),
+                }
+            }
+            // The root is taken from the mutated proof to reach the mutated nodes.
+            let mutated_unit = CryptoProofUnit {
+                root: blake256(&mutated.0[0]),
+                ..unit.clone()
+            };
+            verify(&mutated, &mutated_unit);
+            verify_multi(&mutated, &[mutated_unit]);
+            verify_range(&blake256(&mutated_range.0[0]), &entries[0].0, &entries[10].0, &items, &mutated_range);
+        }
     }
 }
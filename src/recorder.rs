@@ -0,0 +1,168 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::proof::CryptoProof;
+use ccrypto::BLAKE_NULL_RLP;
+use primitives::{Bytes, H256};
+use std::collections::HashSet;
+
+/// Records the nodes which a trie reads from its database.
+///
+/// Attach it to `TrieDB` or `TrieDBMut` with `with_recorder`. The recorded nodes are a witness of the operations,
+/// and replaying the same operations against a database which has only these nodes gives the same results.
+#[derive(Default)]
+pub struct Recorder {
+    nodes: Vec<Bytes>,
+    hashes: HashSet<H256>,
+}
+
+impl Recorder {
+    /// Create a recorder with no nodes.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Record the node of `hash`. A node is recorded only once.
+    /// The empty node is not recorded since every database has it.
+    pub fn record(&mut self, hash: &H256, node_rlp: &[u8]) {
+        if *hash != BLAKE_NULL_RLP && self.hashes.insert(*hash) {
+            self.nodes.push(node_rlp.to_vec());
+        }
+    }
+
+    /// The number of the recorded nodes.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Is no node recorded?
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Take the recorded nodes in the order they were read, and start recording anew.
+    pub fn drain(&mut self) -> CryptoProof {
+        self.hashes.clear();
+        CryptoProof(std::mem::take(&mut self.nodes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proof::proof_db;
+    use crate::*;
+    use ccrypto::blake256;
+    use cdb::MemoryDB;
+    use std::cell::RefCell;
+
+    fn populate(db: &mut MemoryDB, count: usize) -> H256 {
+        let mut root = H256::zero();
+        {
+            let mut t = TrieDBMut::new(db, &mut root);
+            for i in 0..count {
+                t.insert(format!("key{}", i).as_bytes(), format!("value{}", i).as_bytes()).unwrap();
+            }
+        }
+        root
+    }
+
+    #[test]
+    fn replay_reads() {
+        let mut db = MemoryDB::new();
+        let root = populate(&mut db, 100);
+
+        let recorder = RefCell::new(Recorder::new());
+        let keys = ["key1", "key10", "key99", "absent"];
+        let values: Vec<_> = {
+            let t = TrieDB::try_new(&db, &root).unwrap().with_recorder(&recorder);
+            keys.iter().map(|key| t.get(key.as_bytes()).unwrap()).collect()
+        };
+        assert!(!recorder.borrow().is_empty());
+
+        let witness = proof_db(&[recorder.borrow_mut().drain()]);
+        assert!(recorder.borrow().is_empty());
+        let t = TrieDB::try_new(&witness, &root).unwrap();
+        for (key, value) in keys.iter().zip(values) {
+            assert_eq!(t.get(key.as_bytes()).unwrap(), value);
+        }
+        // The leaf of a key which was not read is not in the witness.
+        assert!(t.get(b"key50").is_err());
+    }
+
+    #[test]
+    fn replay_writes() {
+        let mut db = MemoryDB::new();
+        let old_root = populate(&mut db, 100);
+
+        let ops = |t: &mut TrieDBMut<'_>| -> Vec<Option<DBValue>> {
+            let mut results = Vec::new();
+            results.push(t.get(b"key3").unwrap());
+            results.push(t.insert(b"key5", b"new value").unwrap());
+            results.push(t.insert(b"new key", b"value").unwrap());
+            for i in 20..60 {
+                results.push(t.remove(format!("key{}", i).as_bytes()).unwrap());
+            }
+            results.push(t.remove(b"absent").unwrap());
+            results
+        };
+
+        let recorder = RefCell::new(Recorder::new());
+        let mut new_root = old_root;
+        let results = {
+            let mut t = TrieDBMut::from_existing(&mut db, &mut new_root).unwrap().with_recorder(&recorder);
+            ops(&mut t)
+        };
+
+        let mut witness = proof_db(&[recorder.borrow_mut().drain()]);
+        let mut replayed_root = old_root;
+        let replayed_results = {
+            let mut t = TrieDBMut::from_existing(&mut witness, &mut replayed_root).unwrap();
+            ops(&mut t)
+        };
+        assert_eq!(replayed_results, results);
+        assert_eq!(replayed_root, new_root);
+    }
+
+    #[test]
+    fn nodes_in_memory_are_not_recorded() {
+        let mut db = MemoryDB::new();
+        let mut root = populate(&mut db, 100);
+
+        let recorder = RefCell::new(Recorder::new());
+        let mut t = TrieDBMut::from_existing(&mut db, &mut root).unwrap().with_recorder(&recorder);
+        t.insert(b"key1", b"new value").unwrap();
+        recorder.borrow_mut().drain();
+
+        t.make_proof(&blake256(b"key1")).unwrap();
+        t.make_range_proof(&H256::zero(), &H256::from([0xff; 32])).unwrap();
+        assert!(t.is_complete());
+        assert!(recorder.borrow().is_empty());
+    }
+
+    #[test]
+    fn record_once() {
+        let mut db = MemoryDB::new();
+        let root = populate(&mut db, 100);
+
+        let recorder = RefCell::new(Recorder::new());
+        let t = TrieDB::try_new(&db, &root).unwrap().with_recorder(&recorder);
+        t.get(b"key1").unwrap();
+        let len = recorder.borrow().len();
+        t.get(b"key1").unwrap();
+        assert_eq!(recorder.borrow().len(), len);
+    }
+}
@@ -0,0 +1,200 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::hasher::{Blake256, KeyHasher};
+use crate::proof::{CryptoProof, CryptoProofUnit, CryptoStructure};
+use crate::{Trie, TrieDBMut, TrieMut};
+use cdb::HashDB;
+use primitives::H256;
+
+/// The value of every leaf of an `AuthenticatedSet`.
+///
+/// A set needs only the keys, but the value cannot be empty: inserting an empty value removes the key, and a proof
+/// unit with no value is the proof of absence. So a member costs this one byte, which is also a part of the proof
+/// format; `membership_with_hasher` expects it, and changing it changes every root and proof of a set.
+pub const MEMBER: &[u8] = &[0x01];
+
+/// A set of items kept in a trie, which can prove both membership and non-membership with `proof::verify`.
+///
+/// Each item is a key of the trie with the value `MEMBER`, hashed with `H`. Like `TrieDBMut`, the changes are
+/// written on `commit()` or drop, and `root()` and the proofs already show the uncommitted changes.
+///
+/// # Example
+/// ```
+/// use cdb::MemoryDB;
+/// use merkle_trie::proof::verify;
+/// use merkle_trie::set::AuthenticatedSet;
+/// use primitives::H256;
+///
+/// let mut memdb = MemoryDB::new();
+/// let mut root = H256::zero();
+/// let mut set = AuthenticatedSet::new(&mut memdb, &mut root);
+/// set.add(b"validator").unwrap();
+/// set.commit();
+///
+/// let (unit, proof) = set.prove(b"validator").unwrap();
+/// assert_eq!(unit, AuthenticatedSet::membership(set.root(), b"validator", true));
+/// assert!(verify(&proof, &unit));
+/// let (unit, proof) = set.prove(b"stranger").unwrap();
+/// assert!(verify(&proof, &AuthenticatedSet::membership(set.root(), b"stranger", false)));
+/// ```
+pub struct AuthenticatedSet<'db, H: KeyHasher = Blake256> {
+    trie: TrieDBMut<'db, H>,
+}
+
+impl<'db> AuthenticatedSet<'db> {
+    /// Create a new set with backing database `db` and empty `root`.
+    pub fn new(db: &'db mut dyn HashDB, root: &'db mut H256) -> Self {
+        Self::new_with_hasher(db, root)
+    }
+
+    /// Create a set with the backing database `db` and `root`.
+    /// Returns an error if `root` does not exist.
+    pub fn from_existing(db: &'db mut dyn HashDB, root: &'db mut H256) -> crate::Result<Self> {
+        Self::from_existing_with_hasher(db, root)
+    }
+
+    /// The unit which `proof::verify` checks to prove that `item` is in the set of `root` if `member` is true,
+    /// or is not in it otherwise.
+    pub fn membership(root: &H256, item: &[u8], member: bool) -> CryptoProofUnit {
+        Self::membership_with_hasher(root, item, member).expect("Blake256 accepts every item")
+    }
+}
+
+impl<'db, H: KeyHasher> AuthenticatedSet<'db, H> {
+    /// Create a new set which hashes the items with `H`, with backing database `db` and empty `root`.
+    pub fn new_with_hasher(db: &'db mut dyn HashDB, root: &'db mut H256) -> Self {
+        AuthenticatedSet {
+            trie: TrieDBMut::new_with_hasher(db, root),
+        }
+    }
+
+    /// Create a set which hashes the items with `H`, with the backing database `db` and `root`.
+    /// Returns an error if `root` does not exist.
+    pub fn from_existing_with_hasher(db: &'db mut dyn HashDB, root: &'db mut H256) -> crate::Result<Self> {
+        Ok(AuthenticatedSet {
+            trie: TrieDBMut::from_existing_with_hasher(db, root)?,
+        })
+    }
+
+    /// Add `item` to the set. Returns false if it is already in the set.
+    pub fn add(&mut self, item: &[u8]) -> crate::Result<bool> {
+        Ok(self.trie.insert(item, MEMBER)?.is_none())
+    }
+
+    /// Remove `item` from the set. Returns false if it is not in the set.
+    pub fn remove(&mut self, item: &[u8]) -> crate::Result<bool> {
+        Ok(self.trie.remove(item)?.is_some())
+    }
+
+    /// Is `item` in the set? The uncommitted changes are seen.
+    pub fn contains(&self, item: &[u8]) -> crate::Result<bool> {
+        self.trie.contains(item)
+    }
+
+    /// Write the changes to the underlying database.
+    pub fn commit(&mut self) {
+        self.trie.commit()
+    }
+
+    /// Return the root of the set, including the uncommitted changes.
+    pub fn root(&self) -> &H256 {
+        self.trie.root()
+    }
+
+    /// Prove whether `item` is in the set, including the uncommitted changes.
+    /// The unit is the same as `membership_with_hasher(self.root(), item, member)`.
+    pub fn prove(&self, item: &[u8]) -> crate::Result<(CryptoProofUnit, CryptoProof)> {
+        self.trie.make_proof(&H::hash(item)?)
+    }
+
+    /// The unit which `proof::verify` checks to prove that `item` is in the set of `root` if `member` is true,
+    /// or is not in it otherwise, for a set which hashes the items with `H`.
+    pub fn membership_with_hasher(root: &H256, item: &[u8], member: bool) -> crate::Result<CryptoProofUnit> {
+        Ok(CryptoProofUnit {
+            root: *root,
+            key: H::hash(item)?,
+            value: if member {
+                Some(MEMBER.to_vec())
+            } else {
+                None
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hasher::Keccak256;
+    use crate::proof::verify;
+    use crate::triehash::{trie_root, trie_root_with_hasher};
+    use cdb::MemoryDB;
+
+    #[test]
+    fn add_and_remove() {
+        let mut memdb = MemoryDB::new();
+        let mut root = H256::zero();
+        let mut set = AuthenticatedSet::new(&mut memdb, &mut root);
+        assert_eq!(set.add(b"A"), Ok(true));
+        assert_eq!(set.add(b"B"), Ok(true));
+        assert_eq!(set.add(b"A"), Ok(false));
+        assert_eq!(set.contains(b"A"), Ok(true));
+        assert_eq!(set.remove(b"B"), Ok(true));
+        assert_eq!(set.remove(b"B"), Ok(false));
+        assert_eq!(set.contains(b"B"), Ok(false));
+        // The value of a member is a part of the proof format.
+        assert_eq!(*set.root(), trie_root(vec![(b"A", [0x01])]));
+        set.commit();
+        assert_eq!(*set.root(), trie_root(vec![(b"A", MEMBER)]));
+    }
+
+    #[test]
+    fn membership_proofs() {
+        let mut memdb = MemoryDB::new();
+        let mut root = H256::zero();
+        let mut set = AuthenticatedSet::new(&mut memdb, &mut root);
+        for i in 0..100u32 {
+            set.add(&i.to_be_bytes()).unwrap();
+        }
+        set.commit();
+
+        for i in 0..200u32 {
+            let item = i.to_be_bytes();
+            let member = i < 100;
+            let (unit, proof) = set.prove(&item).unwrap();
+            assert_eq!(unit, AuthenticatedSet::membership(set.root(), &item, member));
+            assert!(verify(&proof, &unit));
+            assert!(!verify(&proof, &AuthenticatedSet::membership(set.root(), &item, !member)));
+        }
+    }
+
+    #[test]
+    fn keccak_set() {
+        let mut memdb = MemoryDB::new();
+        let mut root = H256::zero();
+        let mut set = AuthenticatedSet::<Keccak256>::new_with_hasher(&mut memdb, &mut root);
+        set.add(b"A").unwrap();
+        assert_eq!(*set.root(), trie_root_with_hasher::<Keccak256, _, _, _>(vec![(b"A", MEMBER)]).unwrap());
+        assert_ne!(*set.root(), trie_root(vec![(b"A", MEMBER)]));
+
+        for &(item, member) in &[(b"A", true), (b"B", false)] {
+            let (unit, proof) = set.prove(item).unwrap();
+            assert_eq!(unit, AuthenticatedSet::<Keccak256>::membership_with_hasher(set.root(), item, member).unwrap());
+            assert!(verify(&proof, &unit));
+        }
+    }
+}
@@ -86,6 +86,8 @@ impl RawChunk {
                         let encoded = DecodedPathSlice::from_encoded(&node.path_slice).with_slice(slice).encode();
                         trie.insert_raw(Node::Branch(NibbleSlice::from_encoded(&encoded), child))?
                     }
+                    // A trie never has a leaf with an empty value.
+                    Some(Node::Leaf(_, [])) => return Err(ChunkError::InvalidContent.into()),
                     Some(Node::Leaf(slice, data)) => {
                         let encoded = DecodedPathSlice::from_encoded(&node.path_slice).with_slice(slice).encode();
                         trie.insert_raw(Node::Leaf(NibbleSlice::from_encoded(&encoded), data))?
@@ -290,6 +290,7 @@ mod tests {
             for (key, value) in &unique_map {
                 trie.insert(key, value).unwrap();
             }
+            drop(trie);
 
             Snapshot::from_hashdb(&db, root).map(|chunk| (chunk.root, chunk.into_raw_chunk())).collect()
         };
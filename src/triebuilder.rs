@@ -0,0 +1,221 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::nibbleslice::NibbleSlice;
+use crate::node::Node as RlpNode;
+use crate::TrieError;
+use ccrypto::BLAKE_NULL_RLP;
+use cdb::{DBValue, HashDB};
+use primitives::H256;
+
+/// A branch whose children are not all written yet.
+struct OpenBranch {
+    // The nibble of the key which chooses a child.
+    split: usize,
+    children: [Option<H256>; 16],
+}
+
+/// Builds a trie from the leaves sorted by their hashed keys, writing every node to the database exactly once.
+///
+/// The nodes are written from the bottom as soon as no more leaves can be added under them,
+/// so only the nodes on the path of the last leaf are kept in memory.
+/// The root is the same as the one `TrieDBMut` makes from the same leaves.
+///
+/// # Example
+/// ```
+/// use ccrypto::blake256;
+/// use cdb::*;
+/// use merkle_trie::*;
+///
+/// let mut items = vec![(blake256(b"foo"), b"bar"), (blake256(b"baz"), b"qux")];
+/// items.sort();
+///
+/// let mut memdb = MemoryDB::new();
+/// let mut builder = TrieBuilder::new(&mut memdb);
+/// for (key, value) in &items {
+///     builder.insert(key, *value).unwrap();
+/// }
+/// let root = builder.finish();
+///
+/// let t = TrieFactory::readonly(&memdb, &root).unwrap();
+/// assert_eq!(t.get(b"foo").unwrap(), Some(b"bar".to_vec()));
+/// ```
+pub struct TrieBuilder<'db> {
+    db: &'db mut dyn HashDB,
+    // The branches on the path of the last leaf, from the top.
+    branches: Vec<OpenBranch>,
+    // The nibbles of the last key and its value. Its node is written when the next key is known.
+    last: Option<(Vec<u8>, DBValue)>,
+}
+
+impl<'db> TrieBuilder<'db> {
+    /// Create a builder which writes the nodes to `db`.
+    pub fn new(db: &'db mut dyn HashDB) -> Self {
+        TrieBuilder {
+            db,
+            branches: Vec::new(),
+            last: None,
+        }
+    }
+
+    /// Add a leaf of the hashed `key`. An empty value is skipped, as `TrieMut::insert` does not keep it.
+    /// Returns `UnsortedKey` if `key` is not greater than the previous key with a value.
+    pub fn insert(&mut self, key: &H256, value: &[u8]) -> crate::Result<()> {
+        let nibbles = NibbleSlice::new(key).to_vec();
+        if let Some((last, _)) = &self.last {
+            if *last >= nibbles {
+                return Err(TrieError::UnsortedKey(*key))
+            }
+        }
+        if value.is_empty() {
+            return Ok(())
+        }
+        if let Some((last, last_value)) = self.last.take() {
+            let common = last.iter().zip(&nibbles).take_while(|(a, b)| a == b).count();
+            self.close(&last, &last_value, Some(common));
+        }
+        self.last = Some((nibbles, value.to_vec()));
+        Ok(())
+    }
+
+    /// Write the remaining nodes and return the root.
+    pub fn finish(mut self) -> H256 {
+        match self.last.take() {
+            Some((last, last_value)) => self.close(&last, &last_value, None),
+            None => BLAKE_NULL_RLP,
+        }
+    }
+
+    /// Write the leaf of `last` and the branches below the `common` nibble, which no more leaves can be added to.
+    /// Everything is written if `common` is `None`, and the hash of the root is returned.
+    fn close(&mut self, last: &[u8], value: &[u8], common: Option<usize>) -> H256 {
+        let start = self.parent_split(common).map_or(0, |split| split + 1);
+        let (encoded, offset) = NibbleSlice::from_vec(&last[start..]);
+        let mut hash =
+            self.db.insert(&RlpNode::encoded(RlpNode::Leaf(NibbleSlice::new_offset(&encoded, offset), value)));
+
+        loop {
+            let parent = match self.branches.last_mut() {
+                Some(parent) => parent,
+                None => return hash,
+            };
+            parent.children[last[parent.split] as usize] = Some(hash);
+            if Some(parent.split) <= common {
+                return hash
+            }
+
+            let branch = self.branches.pop().expect("The parent exists");
+            let start = self.parent_split(common).map_or(0, |split| split + 1);
+            let (encoded, offset) = NibbleSlice::from_vec(&last[start..branch.split]);
+            let partial = NibbleSlice::new_offset(&encoded, offset);
+            hash = self.db.insert(&RlpNode::encoded(RlpNode::Branch(partial, branch.children.into())));
+        }
+    }
+
+    /// The split of the branch which gets the node being written.
+    /// A new branch is opened at `common` if the node should be separated from the next leaf there.
+    fn parent_split(&mut self, common: Option<usize>) -> Option<usize> {
+        let top = self.branches.last().map(|branch| branch.split);
+        if let Some(common) = common {
+            if top < Some(common) {
+                self.branches.push(OpenBranch {
+                    split: common,
+                    children: [None; 16],
+                });
+                return Some(common)
+            }
+        }
+        top
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hasher::Identity;
+    use crate::triehash::{trie_root, trie_root_with_hasher};
+    use crate::{Trie, TrieDB, TrieDBMut, TrieMut};
+    use ccrypto::blake256;
+    use cdb::MemoryDB;
+    use standardmap::*;
+    use std::collections::BTreeMap;
+
+    fn build(db: &mut MemoryDB, items: &BTreeMap<H256, Vec<u8>>) -> H256 {
+        let mut builder = TrieBuilder::new(db);
+        for (key, value) in items {
+            builder.insert(key, value).unwrap();
+        }
+        builder.finish()
+    }
+
+    #[test]
+    fn empty() {
+        let mut db = MemoryDB::new();
+        assert_eq!(build(&mut db, &BTreeMap::new()), BLAKE_NULL_RLP);
+        assert!(db.keys().is_empty());
+    }
+
+    #[test]
+    fn same_root_as_triedbmut() {
+        let mut seed = H256::zero();
+        for count in &[1, 2, 3, 10, 100, 1000] {
+            let x = StandardMap {
+                alphabet: Alphabet::Custom(b"@QWERTYUIOPASDFGHJKLZXCVBNM[/]^_".to_vec()),
+                min_key: 5,
+                journal_key: 0,
+                value_mode: ValueMode::Index,
+                count: *count,
+            }
+            .make_with(&mut seed);
+
+            let mut expected_db = MemoryDB::new();
+            let mut expected = H256::zero();
+            {
+                let mut t = TrieDBMut::new(&mut expected_db, &mut expected);
+                for (key, value) in &x {
+                    t.insert(key, value).unwrap();
+                }
+            }
+            assert_eq!(expected, trie_root(x.clone()));
+
+            let items = x.iter().map(|(key, value)| (blake256(key), value.clone())).collect();
+            let mut db = MemoryDB::new();
+            let root = build(&mut db, &items);
+            assert_eq!(root, expected);
+
+            // Every node is written once, and nothing else is written.
+            assert_eq!(db.keys(), expected_db.keys().keys().map(|hash| (*hash, 1)).collect());
+            let t = TrieDB::try_new(&db, &root).unwrap();
+            for (key, value) in &x {
+                assert_eq!(t.get(key).unwrap().as_ref(), Some(value));
+            }
+        }
+    }
+
+    #[test]
+    fn unsorted_keys() {
+        let mut db = MemoryDB::new();
+        let mut builder = TrieBuilder::new(&mut db);
+        builder.insert(&H256::from([2; 32]), b"2").unwrap();
+        assert_eq!(builder.insert(&H256::from([1; 32]), b"1"), Err(TrieError::UnsortedKey(H256::from([1; 32]))));
+        assert_eq!(builder.insert(&H256::from([2; 32]), b"2"), Err(TrieError::UnsortedKey(H256::from([2; 32]))));
+        builder.insert(&H256::from([3; 32]), b"3").unwrap();
+        assert_eq!(
+            builder.finish(),
+            trie_root_with_hasher::<Identity, _, _, _>(vec![([2; 32], b"2"), ([3; 32], b"3")]).unwrap()
+        );
+    }
+}
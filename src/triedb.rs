@@ -14,16 +14,21 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use crate::hasher::{Blake256, KeyHasher};
+use crate::iterator::{key_from_nibbles, PreimageIterator, TrieIterator};
 use crate::nibbleslice::NibbleSlice;
 use crate::node::Node as RlpNode;
-use crate::proof::{CryptoProof, CryptoProofUnit, CryptoStructure};
-use crate::{Node, Trie, TrieError};
-use ccrypto::{blake256, BLAKE_NULL_RLP};
-use cdb::HashDB;
+use crate::proof::{range_overlaps, CryptoProof, CryptoProofUnit, CryptoStructure};
+use crate::recorder::Recorder;
+use crate::{IterableTrie, Node, Trie, TrieDBMut, TrieError, TrieMut};
+use ccrypto::BLAKE_NULL_RLP;
+use cdb::{DBValue, HashDB, MemoryDB};
 use lru_cache::LruCache;
 use primitives::Bytes;
 use primitives::H256;
 use std::cell::RefCell;
+use std::collections::HashMap;
+use std::marker::PhantomData;
 
 /// A `Trie` implementation using a generic `HashDB` backing database.
 ///
@@ -43,20 +48,77 @@ use std::cell::RefCell;
 /// assert!(t.contains(b"foo").unwrap());
 /// assert_eq!(t.get(b"foo").unwrap().unwrap(), b"bar".to_vec());
 /// ```
-
-pub(crate) struct TrieDB<'db> {
+///
+/// The keys are hashed with `H`, which is `Blake256` by default. Use `try_new_with_hasher()` for the others.
+pub struct TrieDB<'db, H: KeyHasher = Blake256> {
     db: &'db dyn HashDB,
     root: &'db H256,
     cache: RefCell<LruCache<H256, Vec<u8>>>,
+    preimages: Option<&'db dyn HashDB>,
+    recorder: Option<&'db RefCell<Recorder>>,
+    hasher: PhantomData<H>,
 }
 
 /// Description of what kind of query will be made to the trie.
 type Query<T> = dyn Fn(&[u8]) -> T;
 
+/// A database which reads `overlay` and then `db`, but keeps the written nodes in `overlay`.
+pub(crate) struct DryRunDB<'db> {
+    db: &'db dyn HashDB,
+    overlay: MemoryDB,
+}
+
+impl<'db> DryRunDB<'db> {
+    pub(crate) fn new(db: &'db dyn HashDB, overlay: MemoryDB) -> Self {
+        DryRunDB {
+            db,
+            overlay,
+        }
+    }
+}
+
+impl<'db> HashDB for DryRunDB<'db> {
+    fn keys(&self) -> HashMap<H256, i32> {
+        let mut keys = self.db.keys();
+        for (key, rc) in self.overlay.keys() {
+            *keys.entry(key).or_insert(0) += rc;
+        }
+        keys
+    }
+
+    fn get(&self, key: &H256) -> Option<DBValue> {
+        self.overlay.get(key).or_else(|| self.db.get(key))
+    }
+
+    fn contains(&self, key: &H256) -> bool {
+        self.overlay.contains(key) || self.db.contains(key)
+    }
+
+    fn insert(&mut self, value: &[u8]) -> H256 {
+        self.overlay.insert(value)
+    }
+
+    fn emplace(&mut self, key: H256, value: DBValue) {
+        self.overlay.emplace(key, value)
+    }
+
+    fn remove(&mut self, key: &H256) {
+        self.overlay.remove(key)
+    }
+}
+
 impl<'db> TrieDB<'db> {
     /// Create a new trie with the backing database `db` and `root`
     /// Returns an error if `root` does not exist
     pub fn try_new(db: &'db dyn HashDB, root: &'db H256) -> crate::Result<Self> {
+        Self::try_new_with_hasher(db, root)
+    }
+}
+
+impl<'db, H: KeyHasher> TrieDB<'db, H> {
+    /// Create a new trie which hashes the keys with `H`, with the backing database `db` and `root`.
+    /// Returns an error if `root` does not exist
+    pub fn try_new_with_hasher(db: &'db dyn HashDB, root: &'db H256) -> crate::Result<Self> {
         let cache: RefCell<LruCache<H256, Vec<u8>>> = RefCell::new(LruCache::new(3000));
         if !db.contains(root) {
             Err(TrieError::InvalidStateRoot(*root))
@@ -65,10 +127,49 @@ impl<'db> TrieDB<'db> {
                 db,
                 root,
                 cache,
+                preimages: None,
+                recorder: None,
+                hasher: PhantomData,
             })
         }
     }
 
+    /// Look up the original keys of hashed keys in `preimages`.
+    pub fn with_preimages(mut self, preimages: &'db dyn HashDB) -> Self {
+        self.preimages = Some(preimages);
+        self
+    }
+
+    /// Record every node read by `get` in `recorder`.
+    pub fn with_recorder(mut self, recorder: &'db RefCell<Recorder>) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Compute the root after applying `changes`, where `None` removes the key, without writing to the database.
+    /// The new nodes are kept in memory and dropped after computing the root.
+    pub fn root_after<I, K, V>(&self, changes: I) -> crate::Result<H256>
+    where
+        I: IntoIterator<Item = (K, Option<V>)>,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>, {
+        let mut db = DryRunDB::new(self.db, MemoryDB::new());
+        let mut root = *self.root;
+        {
+            let mut t = TrieDBMut::<H>::from_existing_with_hasher(&mut db, &mut root)?;
+            if let Some(recorder) = self.recorder {
+                t = t.with_recorder(recorder);
+            }
+            for (key, value) in changes {
+                match value {
+                    Some(value) => t.insert(key.as_ref(), value.as_ref())?,
+                    None => t.remove(key.as_ref())?,
+                };
+            }
+        }
+        Ok(root)
+    }
+
     /// Get auxiliary
     fn get_aux<T>(
         &self,
@@ -89,6 +190,9 @@ impl<'db> TrieDB<'db> {
                     self.cache.borrow_mut().insert(hash, (&*node_rlp).to_vec());
                     RlpNode::decoded(&node_rlp)
                 };
+                if let Some(recorder) = self.recorder {
+                    recorder.borrow_mut().record(&hash, &node_rlp);
+                }
 
                 match decoded_rlp {
                     Some(RlpNode::Leaf(partial, value)) => {
@@ -111,47 +215,137 @@ impl<'db> TrieDB<'db> {
             None => Ok(None),
         }
     }
+}
+
+/// Check if every leaf of the trie starting from `hash` exists
+pub(crate) fn is_complete_aux(db: &dyn HashDB, hash: &H256) -> bool {
+    if let Some(node_rlp) = db.get(hash) {
+        match RlpNode::decoded(node_rlp.as_ref()) {
+            Some(RlpNode::Branch(.., children)) => children.iter().flatten().all(|child| is_complete_aux(db, child)),
+            Some(RlpNode::Leaf(..)) => true,
+            None => false,
+        }
+    } else {
+        false
+    }
+}
+
+/// Make the proof of the key whose remaining nibbles are `path`, from the node of `hash`.
+/// It creates a reversed proof for the sake of a more efficient push() operation. (than concat)
+pub(crate) fn make_proof_upto(
+    db: &dyn HashDB,
+    path: &NibbleSlice<'_>,
+    hash: &H256,
+) -> crate::Result<(Option<Bytes>, Vec<Bytes>)> {
+    let node_rlp = db.get(hash).ok_or(TrieError::IncompleteDatabase(*hash))?;
 
-    /// Check if every leaf of the trie starting from `hash` exists
-    fn is_complete_aux(&self, hash: &H256) -> bool {
-        if let Some(node_rlp) = self.db.get(hash) {
-            match RlpNode::decoded(node_rlp.as_ref()) {
-                Some(RlpNode::Branch(.., children)) => {
-                    children.iter().flatten().all(|child| self.is_complete_aux(child))
+    match Node::decoded(&node_rlp) {
+        Some(Node::Leaf(partial, value)) => {
+            if &partial == path {
+                Ok((Some(value.to_vec()), vec![node_rlp]))
+            } else {
+                Ok((None, vec![node_rlp]))
+            }
+        }
+        Some(Node::Branch(partial, children)) => {
+            if path.starts_with(&partial) {
+                match children[path.at(partial.len()) as usize] {
+                    Some(x) => {
+                        let (value, mut reversed_proof) = make_proof_upto(db, &path.mid(partial.len() + 1), &x)?;
+                        reversed_proof.push(node_rlp);
+                        Ok((value, reversed_proof))
+                    }
+                    None => Ok((None, vec![node_rlp])),
                 }
-                Some(RlpNode::Leaf(..)) => true,
-                None => false,
+            } else {
+                // the key diverges from the partial path, so no key under this branch can be the key.
+                Ok((None, vec![node_rlp]))
             }
-        } else {
-            false
         }
+        None => Ok((None, Vec::new())), // empty trie
     }
 }
 
-impl<'db> Trie for TrieDB<'db> {
+/// Push the nodes under the node of `hash`, whose path from the root is `prefix`, which can have a key in `range` to
+/// `proof`, and the leaves in `range` to `items`.
+pub(crate) fn make_range_proof_aux(
+    db: &dyn HashDB,
+    hash: &H256,
+    prefix: Vec<u8>,
+    range: (&[u8], &[u8]),
+    items: &mut Vec<(H256, Bytes)>,
+    proof: &mut Vec<Bytes>,
+) -> crate::Result<()> {
+    let (start, end) = range;
+    let node_rlp = db.get(hash).ok_or(TrieError::IncompleteDatabase(*hash))?;
+
+    match Node::decoded(&node_rlp) {
+        Some(Node::Leaf(partial, value)) => {
+            proof.push(node_rlp.clone());
+            let mut path = prefix;
+            path.extend(partial.to_vec());
+            if start <= &path[..] && &path[..] <= end {
+                items.push((key_from_nibbles(&path), value.to_vec()));
+            }
+        }
+        Some(Node::Branch(partial, children)) => {
+            proof.push(node_rlp.clone());
+            let mut path = prefix;
+            path.extend(partial.to_vec());
+            for (index, child) in children.iter().enumerate() {
+                if let Some(child) = child {
+                    let mut child_prefix = path.clone();
+                    child_prefix.push(index as u8);
+                    if range_overlaps(&child_prefix, start, end) {
+                        make_range_proof_aux(db, child, child_prefix, range, items, proof)?;
+                    }
+                }
+            }
+        }
+        None => {} // empty trie
+    }
+    Ok(())
+}
+
+impl<'db, H: KeyHasher> Trie for TrieDB<'db, H> {
     fn root(&self) -> &H256 {
         self.root
     }
 
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, TrieError> {
-        let path = blake256(key);
+        let path = H::hash(key)?;
         let root = *self.root;
 
         self.get_aux(&NibbleSlice::new(&path), Some(root), &|bytes| bytes.to_vec())
     }
 
+    fn get_preimage(&self, hash: &H256) -> Option<Bytes> {
+        self.preimages.and_then(|preimages| preimages.get(hash))
+    }
+
     fn is_complete(&self) -> bool {
-        *self.root == BLAKE_NULL_RLP || self.is_complete_aux(self.root)
+        *self.root == BLAKE_NULL_RLP || is_complete_aux(self.db, self.root)
     }
 }
 
-impl<'db> CryptoStructure for TrieDB<'db> {
+impl<'db, H: KeyHasher> IterableTrie for TrieDB<'db, H> {
+    fn iter(&self) -> crate::Result<TrieIterator<'_>> {
+        TrieIterator::new(self.db, self.root)
+    }
+
+    fn iter_with_preimages(&self) -> crate::Result<PreimageIterator<'_>> {
+        Ok(self.iter()?.with_preimage_store(self.preimages))
+    }
+}
+
+impl<'db, H: KeyHasher> CryptoStructure for TrieDB<'db, H> {
     /// A proof creation logic for TrieDB.
     /// A proof is basically a list of serialized trie nodes, Vec<Bytes>.
     /// It starts from the one closest to the root and to the leaf. (It may not reach the leaf in absence case.)
     /// Each node can be decoded with RLP. (Note that RLP doesn't guarantee format detail, so you must check our serialization code.)
     /// In case of precense, the list will contain a path from the root to the leaf with the key.
     /// In case of absence, the list will contain a path to the last node that matches the key.
+    /// If the key diverges from the partial path of a branch, the path ends with that branch. (Since version 2.)
     //
     //          (A: [nil])
     //         /         \
@@ -162,40 +356,6 @@ impl<'db> CryptoStructure for TrieDB<'db> {
     // Here, the proof of key 'gmail' will be [(RLP encoding of A), (RLP encoding of B), (RLP encoding of D)]
     // Here, the proof of key 'galbi' (absence) will be [(RLP encoding of A), (RLP encoding of B)]
     fn make_proof(&self, key: &H256) -> crate::Result<(CryptoProofUnit, CryptoProof)> {
-        // it creates a reversed proof for the sake of a more efficient push() operation. (than concat)
-        fn make_proof_upto(
-            db: &dyn HashDB,
-            path: &NibbleSlice<'_>,
-            hash: &H256,
-        ) -> crate::Result<(Option<Bytes>, Vec<Bytes>)> {
-            let node_rlp = db.get(&hash).ok_or_else(|| TrieError::IncompleteDatabase(*hash))?;
-
-            match Node::decoded(&node_rlp) {
-                Some(Node::Leaf(partial, value)) => {
-                    if &partial == path {
-                        Ok((Some(value.to_vec()), vec![node_rlp]))
-                    } else {
-                        Ok((None, vec![node_rlp]))
-                    }
-                }
-                Some(Node::Branch(partial, children)) => {
-                    if path.starts_with(&partial) {
-                        match children[path.at(partial.len()) as usize] {
-                            Some(x) => {
-                                let (value, mut reversed_proof) =
-                                    make_proof_upto(db, &path.mid(partial.len() + 1), &x)?;
-                                reversed_proof.push(node_rlp);
-                                Ok((value, reversed_proof))
-                            }
-                            None => Ok((None, vec![node_rlp])),
-                        }
-                    } else {
-                        Ok((None, Vec::new()))
-                    }
-                }
-                None => Ok((None, Vec::new())), // empty trie
-            }
-        }
         let (value, reversed_proof) = make_proof_upto(self.db, &NibbleSlice::new(&key), self.root())?;
         let unit = CryptoProofUnit {
             root: *self.root(),
@@ -204,6 +364,27 @@ impl<'db> CryptoStructure for TrieDB<'db> {
         };
         Ok((unit, CryptoProof(reversed_proof.iter().rev().cloned().collect())))
     }
+
+    /// A range proof creation logic for TrieDB.
+    /// The proof contains every node which can have a key in `[start, end]`, starting from the root
+    /// in depth-first order. The leaves in the range are returned in the order of their keys.
+    //
+    //          (A: [nil])
+    //         /         \
+    //      (B, g)        \
+    //      /    \         \
+    // (C, iant) (D, mail)  (E, clang)
+    //
+    // Here, the range proof of ['gz', 'z'] will be [(RLP encoding of A), (RLP encoding of B), (RLP encoding of D),
+    // (RLP encoding of E)] and the leaves will be [gmail, nclang].
+    fn make_range_proof(&self, start: &H256, end: &H256) -> crate::Result<(Vec<(H256, Bytes)>, CryptoProof)> {
+        let start = NibbleSlice::new(start).to_vec();
+        let end = NibbleSlice::new(end).to_vec();
+        let mut items = Vec::new();
+        let mut proof = Vec::new();
+        make_range_proof_aux(self.db, self.root(), Vec::new(), (&start, &end), &mut items, &mut proof)?;
+        Ok((items, CryptoProof(proof)))
+    }
 }
 
 #[cfg(test)]
@@ -270,4 +451,39 @@ mod tests {
         let t = TrieDB::try_new(&memdb, &root).unwrap();
         assert!(!t.is_complete());
     }
+
+    #[test]
+    fn root_after_does_not_write() {
+        let mut memdb = MemoryDB::new();
+        let mut root = H256::zero();
+        {
+            let mut t = TrieDBMut::new(&mut memdb, &mut root);
+            for i in 0..100u32 {
+                t.insert(&i.to_be_bytes(), &i.to_le_bytes()).unwrap();
+            }
+        }
+        let keys = memdb.keys();
+        let changes = vec![
+            (0u32.to_be_bytes(), Some(b"modified".to_vec())),
+            (1u32.to_be_bytes(), None),
+            (100u32.to_be_bytes(), Some(b"added".to_vec())),
+        ];
+
+        let t = TrieDB::try_new(&memdb, &root).unwrap();
+        let new_root = t.root_after(changes.clone()).unwrap();
+        assert_eq!(memdb.keys(), keys);
+
+        let mut expected_root = root;
+        {
+            let mut t = TrieDBMut::from_existing(&mut memdb, &mut expected_root).unwrap();
+            for (key, value) in changes {
+                match value {
+                    Some(value) => t.insert(&key, &value).unwrap(),
+                    None => t.remove(&key).unwrap(),
+                };
+            }
+        }
+        assert_ne!(new_root, root);
+        assert_eq!(new_root, expected_root);
+    }
 }
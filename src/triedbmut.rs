@@ -14,286 +14,666 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use crate::hasher::{Blake256, KeyHasher};
+use crate::iterator::{key_from_nibbles, PreimageIterator, TrieIterator};
 use crate::nibbleslice::NibbleSlice;
 use crate::node::Node as RlpNode;
-use crate::triedb::TrieDB;
-use crate::{Trie, TrieError, TrieMut};
+use crate::proof::{range_overlaps, CryptoProof, CryptoProofUnit, CryptoStructure};
+use crate::recorder::Recorder;
+use crate::triedb::{is_complete_aux, make_proof_upto, make_range_proof_aux, DryRunDB};
+use crate::{IterableTrie, Trie, TrieError, TrieMut};
 use ccrypto::{blake256, BLAKE_NULL_RLP};
-use cdb::{DBValue, HashDB};
+use cdb::{DBValue, HashDB, MemoryDB};
+use crossbeam_utils::thread;
 use lru_cache::LruCache;
-use primitives::H256;
+use once_cell::sync::OnceCell;
+use primitives::{Bytes, H256};
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 fn empty_children() -> [Option<H256>; 16] {
     [None; 16]
 }
 
-pub(crate) struct TrieDBMut<'a> {
+fn empty_handles() -> Box<[Option<NodeHandle>; 16]> {
+    Box::default()
+}
+
+/// A node which is loaded from the database or changed after the last commit.
+/// Partial paths are kept as a vector of nibbles.
+#[derive(Clone)]
+enum OwnedNode {
+    Leaf(Vec<u8>, DBValue),
+    Branch(Vec<u8>, Box<[Option<NodeHandle>; 16]>),
+}
+
+impl OwnedNode {
+    fn from_rlp(node: RlpNode<'_>) -> Self {
+        match node {
+            RlpNode::Leaf(partial, value) => OwnedNode::Leaf(partial.to_vec(), value.to_vec()),
+            RlpNode::Branch(partial, children) => {
+                let mut handles = empty_handles();
+                for (handle, child) in handles.iter_mut().zip(children.iter()) {
+                    *handle = child.map(NodeHandle::Hash);
+                }
+                OwnedNode::Branch(partial.to_vec(), handles)
+            }
+        }
+    }
+
+    fn partial_mut(&mut self) -> &mut Vec<u8> {
+        match self {
+            OwnedNode::Leaf(partial, _) | OwnedNode::Branch(partial, _) => partial,
+        }
+    }
+}
+
+#[derive(Clone)]
+enum NodeHandle {
+    /// A node which is in the database and not loaded yet.
+    Hash(H256),
+    /// A node in memory, with its hash once it is hashed. The hash is forgotten when the node is changed.
+    InMemory(Arc<OwnedNode>, OnceCell<H256>),
+}
+
+impl NodeHandle {
+    fn in_memory(node: OwnedNode) -> Self {
+        NodeHandle::InMemory(Arc::new(node), OnceCell::new())
+    }
+
+    fn leaf(path: NibbleSlice<'_>, value: &[u8]) -> Self {
+        NodeHandle::in_memory(OwnedNode::Leaf(path.to_vec(), value.to_vec()))
+    }
+
+    /// The hash of the node. A node in memory is hashed on the first call, after its children.
+    fn hash(&self) -> &H256 {
+        match self {
+            NodeHandle::Hash(hash) => hash,
+            NodeHandle::InMemory(node, hash) => hash.get_or_init(|| blake256(encode_node(node, |child| *child.hash()))),
+        }
+    }
+}
+
+/// How many nibbles at the beginning of `partial` match `path`?
+fn common_prefix(partial: &[u8], path: &NibbleSlice<'_>) -> usize {
+    partial.iter().zip((0..path.len()).map(|i| path.at(i))).take_while(|(a, b)| **a == *b).count()
+}
+
+fn is_same_path(partial: &[u8], path: &NibbleSlice<'_>) -> bool {
+    partial.len() == path.len() && common_prefix(partial, path) == partial.len()
+}
+
+/// Encode `node` with the hashes of its children given by `child_hash`.
+fn encode_node(node: &OwnedNode, mut child_hash: impl FnMut(&NodeHandle) -> H256) -> Bytes {
+    match node {
+        OwnedNode::Leaf(partial, value) => {
+            let (encoded, offset) = NibbleSlice::from_vec(partial);
+            RlpNode::encoded(RlpNode::Leaf(NibbleSlice::new_offset(&encoded, offset), value))
+        }
+        OwnedNode::Branch(partial, children) => {
+            let mut hashes = empty_children();
+            for (hash, child) in hashes.iter_mut().zip(children.iter()) {
+                *hash = child.as_ref().map(&mut child_hash);
+            }
+            let (encoded, offset) = NibbleSlice::from_vec(partial);
+            RlpNode::encoded(RlpNode::Branch(NibbleSlice::new_offset(&encoded, offset), hashes.into()))
+        }
+    }
+}
+
+/// Encode the nodes in memory from the bottom, pushing them to `nodes`, and return the hash of `handle`.
+fn encode_nodes(handle: &NodeHandle, nodes: &mut Vec<(H256, Bytes)>) -> H256 {
+    let (node, cached) = match handle {
+        NodeHandle::Hash(hash) => return *hash,
+        NodeHandle::InMemory(node, cached) => (node, cached),
+    };
+
+    let node_rlp = encode_node(node, |child| encode_nodes(child, nodes));
+    let hash = *cached.get_or_init(|| blake256(&node_rlp));
+    nodes.push((hash, node_rlp));
+    hash
+}
+
+/// The number of changes under a child of the root, below which `apply_parallel()` applies them in the calling
+/// thread instead of a new one.
+pub const PARALLEL_CUTOFF: usize = 64;
+
+/// A subtree changed by `apply_in_worker()`.
+struct WorkerOutput {
+    slot: Option<NodeHandle>,
+    // The nodes loaded from the database, which are obsoleted on commit unless they are unchanged.
+    obsoleted: Vec<H256>,
+    // The old values in the order of the changes.
+    old_values: Vec<Option<DBValue>>,
+}
+
+/// Apply the `changes` sorted by their paths to the subtree in `slot`, where the first `depth` nibbles of the paths are
+/// already consumed, and hash the changed nodes. It only reads `db`, so the subtrees can be done in parallel.
+fn apply_in_worker<H: KeyHasher>(
+    db: &dyn HashDB,
+    mut slot: Option<NodeHandle>,
+    depth: usize,
+    changes: &[BatchChange<'_>],
+) -> crate::Result<WorkerOutput> {
+    let changes: Vec<_> = changes
+        .iter()
+        .enumerate()
+        .map(|(index, change)| BatchChange {
+            path: change.path,
+            value: change.value,
+            index,
+        })
+        .collect();
+    let mut old_values = vec![None; changes.len()];
+
+    let mut db = DryRunDB::new(db, MemoryDB::new());
+    let mut root = BLAKE_NULL_RLP;
+    let mut worker = TrieDBMut::<H>::new_with_hasher(&mut db, &mut root);
+    worker.apply_batch_aux(&mut slot, depth, &changes, &mut old_values)?;
+    if let Some(handle) = &slot {
+        // Hash the changed nodes in this thread, not to hash them on commit.
+        handle.hash();
+    }
+    Ok(WorkerOutput {
+        slot,
+        obsoleted: mem::take(&mut worker.obsoleted),
+        old_values,
+    })
+}
+
+/// The nodes which a commit of `TrieDBMut` writes and makes unreachable.
+#[derive(Debug, Default, PartialEq)]
+pub struct ChangeSet {
+    /// The nodes written to the database.
+    pub inserted: Vec<(H256, DBValue)>,
+    /// The nodes which are not reachable from the new root anymore.
+    /// They are removed from the database only if the trie is made `with_pruning()`.
+    pub obsoleted: Vec<H256>,
+}
+
+/// A change of `TrieMut::apply_batch`.
+struct BatchChange<'c> {
+    path: H256,
+    value: Option<&'c [u8]>,
+    // The position in the input, where the old value is returned.
+    index: usize,
+}
+
+/// The source of the ids which tell the savepoints of a `TrieDBMut` from the others.
+static NEXT_TRIE_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A state of `TrieDBMut` which the changes after it can be undone to.
+/// It belongs to the trie which made it, and the other tries ignore it.
+pub struct Savepoint {
+    trie: usize,
+    id: usize,
+}
+
+struct SavedState {
+    id: usize,
+    root_handle: Option<NodeHandle>,
+    // The length of `TrieDBMut::obsoleted` at the savepoint.
+    obsoleted: usize,
+}
+
+/// A `TrieMut` implementation using a generic `HashDB` backing database.
+///
+/// The modified nodes are kept in memory, and only the nodes reachable from the new root are written to
+/// the database by `commit()`, which is also called when the trie is dropped.
+/// The reads, `root()`, `iter()`, `is_complete()` and the proofs see the uncommitted changes.
+/// The changed nodes are hashed only when they are needed, by `root()`, the proofs or `commit()`.
+///
+/// The uncommitted changes can be undone with `savepoint()` and `rollback_to()`.
+///
+/// The keys are hashed with `H`, which is `Blake256` by default. Use `new_with_hasher()` and
+/// `from_existing_with_hasher()` for the others.
+pub struct TrieDBMut<'a, H: KeyHasher = Blake256> {
     db: &'a mut dyn HashDB,
-    // When Trie is empty, root has None.
+    // The root of the last commit. `root()` is the root with the uncommitted changes.
     root: &'a mut H256,
+    // When Trie is empty, root_handle has None.
+    root_handle: Option<NodeHandle>,
     cache: LruCache<H256, Vec<u8>>,
+    // Original keys of the inserted keys, stored under their hashes.
+    preimages: Option<&'a mut dyn HashDB>,
+    recorder: Option<&'a RefCell<Recorder>>,
+    // Hashes of the loaded nodes, which are obsoleted on commit unless they are unchanged.
+    obsoleted: Vec<H256>,
+    pruning: bool,
+    // The id of this trie, which its savepoints are tagged with.
+    id: usize,
+    savepoints: Vec<SavedState>,
+    next_savepoint: usize,
+    hasher: PhantomData<H>,
 }
 
 impl<'a> TrieDBMut<'a> {
     /// Create a new trie with backing database `db` and empty `root`.
     pub fn new(db: &'a mut dyn HashDB, root: &'a mut H256) -> Self {
+        Self::new_with_hasher(db, root)
+    }
+
+    /// Create a new trie with the backing database `db` and `root.
+    /// Returns an error if `root` does not exist.
+    pub fn from_existing(db: &'a mut dyn HashDB, root: &'a mut H256) -> crate::Result<Self> {
+        Self::from_existing_with_hasher(db, root)
+    }
+}
+
+impl<'a, H: KeyHasher> TrieDBMut<'a, H> {
+    /// Create a new trie which hashes the keys with `H`, with backing database `db` and empty `root`.
+    pub fn new_with_hasher(db: &'a mut dyn HashDB, root: &'a mut H256) -> Self {
         *root = BLAKE_NULL_RLP;
 
         let cache: LruCache<H256, Vec<u8>> = LruCache::new(3000);
         TrieDBMut {
             db,
             root,
+            root_handle: None,
             cache,
+            preimages: None,
+            recorder: None,
+            obsoleted: Vec::new(),
+            pruning: false,
+            id: NEXT_TRIE_ID.fetch_add(1, Ordering::Relaxed),
+            savepoints: Vec::new(),
+            next_savepoint: 0,
+            hasher: PhantomData,
         }
     }
 
-    /// Create a new trie with the backing database `db` and `root.
+    /// Create a new trie which hashes the keys with `H`, with the backing database `db` and `root`.
     /// Returns an error if `root` does not exist.
-    pub fn from_existing(db: &'a mut dyn HashDB, root: &'a mut H256) -> crate::Result<Self> {
+    pub fn from_existing_with_hasher(db: &'a mut dyn HashDB, root: &'a mut H256) -> crate::Result<Self> {
         if !db.contains(root) {
             return Err(TrieError::InvalidStateRoot(*root))
         }
 
+        let root_handle = if *root == BLAKE_NULL_RLP {
+            None
+        } else {
+            Some(NodeHandle::Hash(*root))
+        };
         let cache: LruCache<H256, Vec<u8>> = LruCache::new(3000);
         Ok(TrieDBMut {
             db,
             root,
+            root_handle,
             cache,
+            preimages: None,
+            recorder: None,
+            obsoleted: Vec::new(),
+            pruning: false,
+            id: NEXT_TRIE_ID.fetch_add(1, Ordering::Relaxed),
+            savepoints: Vec::new(),
+            next_savepoint: 0,
+            hasher: PhantomData,
         })
     }
 
+    /// Record the original key of every inserted key in `preimages`.
+    pub fn with_preimages(mut self, preimages: &'a mut dyn HashDB) -> Self {
+        self.preimages = Some(preimages);
+        self
+    }
+
+    /// Record every node read by `get`, `insert` and `remove` in `recorder`.
+    pub fn with_recorder(mut self, recorder: &'a RefCell<Recorder>) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Remove the nodes which are not reachable anymore, the obsoleted nodes of `ChangeSet`, from `db` on commit.
+    /// Since a node can be shared by several tries, `db` should count the references of its nodes.
+    pub fn with_pruning(mut self) -> Self {
+        self.pruning = true;
+        self
+    }
+
+    /// Save the current state with the uncommitted changes, to undo the changes after it with `rollback_to()`.
+    /// Committing releases all savepoints.
+    ///
+    /// Only the trie is restored. The original keys written to the preimage store after the savepoint are kept,
+    /// which is harmless since a preimage is looked up only by the hash of its key.
+    pub fn savepoint(&mut self) -> Savepoint {
+        let id = self.next_savepoint;
+        self.next_savepoint += 1;
+        self.savepoints.push(SavedState {
+            id,
+            root_handle: self.root_handle.clone(),
+            obsoleted: self.obsoleted.len(),
+        });
+        Savepoint {
+            trie: self.id,
+            id,
+        }
+    }
+
+    /// Undo the changes after `savepoint`. The savepoints made after it are released.
+    /// Returns false and does nothing if `savepoint` is already released or made by another trie.
+    pub fn rollback_to(&mut self, savepoint: Savepoint) -> bool {
+        match self.take_savepoint(savepoint) {
+            Some(saved) => {
+                self.root_handle = saved.root_handle;
+                self.obsoleted.truncate(saved.obsoleted);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Keep the changes after `savepoint` and forget it. The savepoints made after it are released as well.
+    /// Returns false and does nothing if `savepoint` is already released or made by another trie.
+    pub fn release(&mut self, savepoint: Savepoint) -> bool {
+        self.take_savepoint(savepoint).is_some()
+    }
+
+    fn take_savepoint(&mut self, savepoint: Savepoint) -> Option<SavedState> {
+        if savepoint.trie != self.id {
+            return None
+        }
+        let index = self.savepoints.iter().position(|saved| saved.id == savepoint.id)?;
+        self.savepoints.truncate(index + 1);
+        self.savepoints.pop()
+    }
+
+    /// The encoded nodes in memory, which are not written to the database until commit.
+    fn pending_nodes(&self) -> MemoryDB {
+        let mut overlay = MemoryDB::new();
+        if let Some(handle) = &self.root_handle {
+            let mut nodes = Vec::new();
+            encode_nodes(handle, &mut nodes);
+            for (hash, node_rlp) in nodes {
+                overlay.emplace(hash, node_rlp);
+            }
+        }
+        overlay
+    }
+
+    /// Same as `is_complete_aux()` of `TrieDB`, but the nodes in memory are walked without encoding them.
+    fn is_complete_in_memory(&self, handle: &NodeHandle) -> bool {
+        match handle {
+            NodeHandle::Hash(hash) => is_complete_aux(self.db, hash),
+            NodeHandle::InMemory(node, _) => match &**node {
+                OwnedNode::Leaf(..) => true,
+                OwnedNode::Branch(_, children) => {
+                    children.iter().flatten().all(|child| self.is_complete_in_memory(child))
+                }
+            },
+        }
+    }
+
+    /// Same as `make_proof_upto()` of `TrieDB`, but only the nodes in memory on the path are encoded.
+    /// The nodes in memory are not recorded, since they are not read from the database.
+    fn make_proof_in_memory(
+        &self,
+        handle: &NodeHandle,
+        path: &NibbleSlice<'_>,
+    ) -> crate::Result<(Option<Bytes>, Vec<Bytes>)> {
+        let node = match handle {
+            NodeHandle::Hash(hash) => return make_proof_upto(self.db, path, hash),
+            NodeHandle::InMemory(node, _) => node,
+        };
+        let node_rlp = encode_node(node, |child| *child.hash());
+        match &**node {
+            OwnedNode::Leaf(partial, value) if is_same_path(partial, path) => Ok((Some(value.clone()), vec![node_rlp])),
+            OwnedNode::Branch(partial, children) if common_prefix(partial, path) == partial.len() => {
+                match &children[path.at(partial.len()) as usize] {
+                    Some(child) => {
+                        let (value, mut reversed_proof) =
+                            self.make_proof_in_memory(child, &path.mid(partial.len() + 1))?;
+                        reversed_proof.push(node_rlp);
+                        Ok((value, reversed_proof))
+                    }
+                    None => Ok((None, vec![node_rlp])),
+                }
+            }
+            // The key is not under this node.
+            _ => Ok((None, vec![node_rlp])),
+        }
+    }
+
+    /// Same as `make_range_proof_aux()` of `TrieDB`, but only the nodes in memory in the range are encoded.
+    fn make_range_proof_in_memory(
+        &self,
+        handle: &NodeHandle,
+        prefix: Vec<u8>,
+        range: (&[u8], &[u8]),
+        items: &mut Vec<(H256, Bytes)>,
+        proof: &mut Vec<Bytes>,
+    ) -> crate::Result<()> {
+        let node = match handle {
+            NodeHandle::Hash(hash) => return make_range_proof_aux(self.db, hash, prefix, range, items, proof),
+            NodeHandle::InMemory(node, _) => node,
+        };
+        let (start, end) = range;
+        proof.push(encode_node(node, |child| *child.hash()));
+        let mut path = prefix;
+        match &**node {
+            OwnedNode::Leaf(partial, value) => {
+                path.extend(partial);
+                if start <= &path[..] && &path[..] <= end {
+                    items.push((key_from_nibbles(&path), value.clone()));
+                }
+            }
+            OwnedNode::Branch(partial, children) => {
+                path.extend(partial);
+                for (index, child) in children.iter().enumerate() {
+                    if let Some(child) = child {
+                        let mut child_prefix = path.clone();
+                        child_prefix.push(index as u8);
+                        if range_overlaps(&child_prefix, start, end) {
+                            self.make_range_proof_in_memory(child, child_prefix, range, items, proof)?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn record(&self, hash: &H256, node_rlp: &[u8]) {
+        if let Some(recorder) = self.recorder {
+            recorder.borrow_mut().record(hash, node_rlp);
+        }
+    }
+
+    /// Load the node from the database if it is not in memory yet.
+    fn resolve<'n>(&mut self, handle: &'n mut NodeHandle) -> crate::Result<&'n mut OwnedNode> {
+        if let NodeHandle::Hash(hash) = *handle {
+            let node_rlp = match self.cache.get_mut(&hash) {
+                Some(node_rlp) => node_rlp.clone(),
+                None => {
+                    let node_rlp = self.db.get(&hash).ok_or(TrieError::IncompleteDatabase(hash))?;
+                    self.cache.insert(hash, node_rlp.clone());
+                    node_rlp
+                }
+            };
+            self.record(&hash, &node_rlp);
+
+            let node = RlpNode::decoded(&node_rlp).ok_or(TrieError::IncompleteDatabase(hash))?;
+            self.obsoleted.push(hash);
+            *handle = NodeHandle::in_memory(OwnedNode::from_rlp(node));
+        }
+        match handle {
+            NodeHandle::InMemory(node, hash) => {
+                // The node is going to be changed.
+                *hash = OnceCell::new();
+                Ok(Arc::make_mut(node))
+            }
+            NodeHandle::Hash(_) => unreachable!("The node is loaded above"),
+        }
+    }
+
+    /// Get auxiliary
+    fn get_aux(&self, handle: &NodeHandle, path: NibbleSlice<'_>) -> crate::Result<Option<DBValue>> {
+        let loaded;
+        let node = match handle {
+            NodeHandle::Hash(hash) => {
+                let node_rlp = self.db.get(hash).ok_or(TrieError::IncompleteDatabase(*hash))?;
+                self.record(hash, &node_rlp);
+                match RlpNode::decoded(&node_rlp) {
+                    Some(node) => loaded = OwnedNode::from_rlp(node),
+                    None => return Ok(None),
+                }
+                &loaded
+            }
+            NodeHandle::InMemory(node, _) => &**node,
+        };
+
+        match node {
+            OwnedNode::Leaf(partial, value) => {
+                if is_same_path(partial, &path) {
+                    Ok(Some(value.clone()))
+                } else {
+                    Ok(None)
+                }
+            }
+            OwnedNode::Branch(partial, children) => {
+                if common_prefix(partial, &path) < partial.len() {
+                    return Ok(None)
+                }
+                match &children[path.at(partial.len()) as usize] {
+                    Some(child) => self.get_aux(child, path.mid(partial.len() + 1)),
+                    None => Ok(None),
+                }
+            }
+        }
+    }
+
     /// Insert auxiliary
     fn insert_aux(
         &mut self,
+        handle: &mut NodeHandle,
         path: NibbleSlice<'_>,
         insert_value: &[u8],
-        cur_node_hash: Option<H256>,
         old_val: &mut Option<DBValue>,
-    ) -> crate::Result<H256> {
-        match cur_node_hash {
-            Some(hash) => {
-                // FIXME: Refactoring is required to reduce access to the cache.
-                //        the current code queries the cache twice when the data is cached.
-                let node_rlp;
-                let decoded_rlp = if self.cache.contains_key(&hash) {
-                    node_rlp = self.cache.get_mut(&hash).unwrap().to_vec();
-                    RlpNode::decoded(&node_rlp)
-                } else {
-                    node_rlp = self.db.get(&hash).ok_or_else(|| TrieError::IncompleteDatabase(hash))?;
-                    self.cache.insert(hash, (&*node_rlp).to_vec());
-                    RlpNode::decoded(&node_rlp)
-                };
-
-                match decoded_rlp {
-                    Some(RlpNode::Leaf(partial, value)) => {
-                        // Renew the Leaf
-                        if partial == path {
-                            let node = RlpNode::Leaf(path, insert_value);
-                            let node_rlp = RlpNode::encoded(node);
-                            let hash = self.db.insert(&node_rlp);
-
-                            self.cache.insert(hash, node_rlp);
-                            *old_val = Some(value.to_vec());
-
-                            Ok(hash)
-                        } else {
-                            // Make branch node and insert Leaves
-                            let common = partial.common_prefix(&path);
-                            let mut new_child = empty_children();
-                            let new_partial = partial.mid(common);
-                            let new_path = path.mid(common);
-
-                            new_child[new_partial.at(0) as usize] = Some(self.insert_aux(
-                                new_partial.mid(1),
-                                value,
-                                new_child[new_partial.at(0) as usize],
-                                old_val,
-                            )?);
-                            new_child[new_path.at(0) as usize] = Some(self.insert_aux(
-                                new_path.mid(1),
-                                insert_value,
-                                new_child[new_path.at(0) as usize],
-                                old_val,
-                            )?);
-
-                            let node_rlp = RlpNode::encoded_until(RlpNode::Branch(partial, new_child.into()), common);
-                            let hash = self.db.insert(&node_rlp);
-                            self.cache.insert(hash, node_rlp);
-
-                            Ok(hash)
-                        }
-                    }
-                    Some(RlpNode::Branch(partial, mut children)) => {
-                        let common = partial.common_prefix(&path);
-
-                        // Make new branch node and insert leaf and branch with new path
-                        if common < partial.len() {
-                            let mut new_child = empty_children();
-                            let new_partial = partial.mid(common);
-                            let new_path = path.mid(common);
-                            let o_branch = RlpNode::Branch(new_partial.mid(1), children);
-
-                            let mut node_rlp = RlpNode::encoded(o_branch);
-                            let b_hash = self.db.insert(&node_rlp);
-                            self.cache.insert(b_hash, node_rlp);
-
-                            new_child[new_partial.at(0) as usize] = Some(b_hash);
-                            new_child[new_path.at(0) as usize] = Some(self.insert_aux(
-                                new_path.mid(1),
-                                insert_value,
-                                new_child[new_path.at(0) as usize],
-                                old_val,
-                            )?);
-
-                            node_rlp = RlpNode::encoded_until(RlpNode::Branch(partial, new_child.into()), common);
-                            let hash = self.db.insert(&node_rlp);
-                            self.cache.insert(hash, node_rlp);
-
-                            Ok(hash)
-                        } else {
-                            // Insert leaf into the branch node
-                            let new_path = path.mid(common);
-
-                            children[new_path.at(0) as usize] = Some(self.insert_aux(
-                                new_path.mid(1),
-                                insert_value,
-                                children[new_path.at(0) as usize],
-                                old_val,
-                            )?);
-
-                            let new_branch = RlpNode::Branch(partial, children);
-                            let node_rlp = RlpNode::encoded(new_branch);
-                            let hash = self.db.insert(&node_rlp);
-                            self.cache.insert(hash, node_rlp);
-
-                            Ok(hash)
-                        }
-                    }
-                    None => {
-                        let node = RlpNode::Leaf(path, insert_value);
-                        let node_rlp = RlpNode::encoded(node);
-                        let hash = self.db.insert(&node_rlp);
-                        self.cache.insert(hash, node_rlp);
-
-                        Ok(hash)
-                    }
+    ) -> crate::Result<()> {
+        match self.resolve(handle)? {
+            OwnedNode::Leaf(partial, value) => {
+                // Renew the Leaf
+                if is_same_path(partial, &path) {
+                    *old_val = Some(mem::replace(value, insert_value.to_vec()));
+                    return Ok(())
                 }
+
+                // Make branch node and insert Leaves
+                let common = common_prefix(partial, &path);
+                let leaf_partial = partial.split_off(common);
+                let mut children = empty_handles();
+                children[leaf_partial[0] as usize] =
+                    Some(NodeHandle::in_memory(OwnedNode::Leaf(leaf_partial[1..].to_vec(), mem::take(value))));
+                children[path.at(common) as usize] = Some(NodeHandle::leaf(path.mid(common + 1), insert_value));
+                *handle = NodeHandle::in_memory(OwnedNode::Branch(mem::take(partial), children));
+                Ok(())
             }
-            None => {
-                let node = RlpNode::Leaf(path, insert_value);
-                let node_rlp = RlpNode::encoded(node);
-                let hash = self.db.insert(&node_rlp);
-                self.cache.insert(hash, node_rlp);
+            OwnedNode::Branch(partial, children) => {
+                let common = common_prefix(partial, &path);
+
+                // Make new branch node and insert leaf and branch with new path
+                if common < partial.len() {
+                    let branch_partial = partial.split_off(common);
+                    let branch_children = mem::replace(children, empty_handles());
+                    children[branch_partial[0] as usize] =
+                        Some(NodeHandle::in_memory(OwnedNode::Branch(branch_partial[1..].to_vec(), branch_children)));
+                    children[path.at(common) as usize] = Some(NodeHandle::leaf(path.mid(common + 1), insert_value));
+                    return Ok(())
+                }
 
-                Ok(hash)
+                // Insert leaf into the branch node
+                let index = path.at(common) as usize;
+                if let Some(child) = &mut children[index] {
+                    self.insert_aux(child, path.mid(common + 1), insert_value, old_val)
+                } else {
+                    children[index] = Some(NodeHandle::leaf(path.mid(common + 1), insert_value));
+                    Ok(())
+                }
             }
         }
     }
 
+    /// Insert a node of a valid trie. The value of a leaf must not be empty.
     pub(crate) fn insert_raw(&mut self, node: RlpNode) -> crate::Result<Option<DBValue>> {
+        debug_assert!(
+            match &node {
+                RlpNode::Leaf(_, value) => !value.is_empty(),
+                RlpNode::Branch(..) => true,
+            },
+            "An empty leaf is the same as no leaf"
+        );
         let mut old_val = None;
-        let cur_hash = *self.root;
-        *self.root = self.insert_raw_aux(node, Some(cur_hash), &mut old_val)?;
+        let mut root_handle = self.root_handle.take();
+        let result = match &mut root_handle {
+            Some(handle) => self.insert_raw_aux(handle, node, &mut old_val),
+            None => {
+                root_handle = Some(NodeHandle::in_memory(OwnedNode::from_rlp(node)));
+                Ok(())
+            }
+        };
+        self.root_handle = root_handle;
+        result?;
 
         Ok(old_val)
     }
 
     fn insert_raw_aux(
         &mut self,
+        handle: &mut NodeHandle,
         node: RlpNode,
-        cur_node_hash: Option<H256>,
         old_val: &mut Option<DBValue>,
-    ) -> crate::Result<H256> {
+    ) -> crate::Result<()> {
         let path = match &node {
-            RlpNode::Leaf(slice, _) | RlpNode::Branch(slice, _) => slice,
+            RlpNode::Leaf(slice, _) | RlpNode::Branch(slice, _) => *slice,
         };
 
-        match cur_node_hash {
-            Some(hash) => {
-                let existing_node_rlp = self.db.get(&hash).ok_or_else(|| TrieError::IncompleteDatabase(hash))?;
-                match RlpNode::decoded(&existing_node_rlp) {
-                    Some(RlpNode::Leaf(partial, value)) => {
-                        // Renew the Leaf
-                        if &partial == path {
-                            let hash = self.db.insert(&RlpNode::encoded(node));
-                            *old_val = Some(existing_node_rlp);
-                            Ok(hash)
-                        } else {
-                            // Make branch node and insert Leaves
-                            let common = partial.common_prefix(&path);
-                            let mut new_child = empty_children();
-                            let new_partial = partial.mid(common);
-                            let new_path = path.mid(common);
-                            new_child[new_partial.at(0) as usize] = Some(self.insert_aux(
-                                new_partial.mid(1),
-                                value,
-                                new_child[new_partial.at(0) as usize],
-                                old_val,
-                            )?);
-                            new_child[new_path.at(0) as usize] = Some(self.insert_raw_aux(
-                                node.mid(common + 1),
-                                new_child[new_path.at(0) as usize],
-                                old_val,
-                            )?);
-
-                            let hash = self
-                                .db
-                                .insert(&RlpNode::encoded_until(RlpNode::Branch(partial, new_child.into()), common));
-
-                            Ok(hash)
-                        }
-                    }
-                    Some(RlpNode::Branch(partial, mut children)) => {
-                        let common = partial.common_prefix(&path);
-
-                        // Make new branch node and insert leaf and branch with new path
-                        if common < partial.len() {
-                            let mut new_child = empty_children();
-                            let new_partial = partial.mid(common);
-                            let new_path = path.mid(common);
-                            let o_branch = RlpNode::Branch(new_partial.mid(1), children);
-
-                            let b_hash = self.db.insert(&RlpNode::encoded(o_branch));
-
-                            new_child[new_partial.at(0) as usize] = Some(b_hash);
-                            new_child[new_path.at(0) as usize] = Some(self.insert_raw_aux(
-                                node.mid(common + 1),
-                                new_child[new_path.at(0) as usize],
-                                old_val,
-                            )?);
-
-                            let hash = self
-                                .db
-                                .insert(&RlpNode::encoded_until(RlpNode::Branch(partial, new_child.into()), common));
-
-                            Ok(hash)
-                        } else {
-                            // Insert leaf into the branch node
-                            let new_path = path.mid(common);
-
-                            children[new_path.at(0) as usize] = Some(self.insert_raw_aux(
-                                node.mid(common + 1),
-                                children[new_path.at(0) as usize],
-                                old_val,
-                            )?);
-
-                            let new_branch = RlpNode::Branch(partial, children);
-                            let node_rlp = RlpNode::encoded(new_branch);
-                            let hash = self.db.insert(&node_rlp);
-
-                            Ok(hash)
-                        }
-                    }
-                    None => {
-                        let hash = self.db.insert(&RlpNode::encoded(node));
-                        Ok(hash)
-                    }
+        match self.resolve(handle)? {
+            OwnedNode::Leaf(partial, value) => {
+                // Renew the Leaf
+                if is_same_path(partial, &path) {
+                    let (encoded, offset) = NibbleSlice::from_vec(partial);
+                    *old_val = Some(RlpNode::encoded(RlpNode::Leaf(NibbleSlice::new_offset(&encoded, offset), value)));
+                    *handle = NodeHandle::in_memory(OwnedNode::from_rlp(node));
+                    return Ok(())
                 }
+
+                // Make branch node and insert Leaves
+                let common = common_prefix(partial, &path);
+                let leaf_partial = partial.split_off(common);
+                let mut children = empty_handles();
+                children[leaf_partial[0] as usize] =
+                    Some(NodeHandle::in_memory(OwnedNode::Leaf(leaf_partial[1..].to_vec(), mem::take(value))));
+                children[path.at(common) as usize] =
+                    Some(NodeHandle::in_memory(OwnedNode::from_rlp(node.mid(common + 1))));
+                *handle = NodeHandle::in_memory(OwnedNode::Branch(mem::take(partial), children));
+                Ok(())
             }
-            None => {
-                let hash = self.db.insert(&RlpNode::encoded(node));
-                Ok(hash)
+            OwnedNode::Branch(partial, children) => {
+                let common = common_prefix(partial, &path);
+
+                // Make new branch node and insert leaf and branch with new path
+                if common < partial.len() {
+                    let branch_partial = partial.split_off(common);
+                    let branch_children = mem::replace(children, empty_handles());
+                    children[branch_partial[0] as usize] =
+                        Some(NodeHandle::in_memory(OwnedNode::Branch(branch_partial[1..].to_vec(), branch_children)));
+                    children[path.at(common) as usize] =
+                        Some(NodeHandle::in_memory(OwnedNode::from_rlp(node.mid(common + 1))));
+                    return Ok(())
+                }
+
+                // Insert leaf into the branch node
+                let index = path.at(common) as usize;
+                if let Some(child) = &mut children[index] {
+                    self.insert_raw_aux(child, node.mid(common + 1), old_val)
+                } else {
+                    children[index] = Some(NodeHandle::in_memory(OwnedNode::from_rlp(node.mid(common + 1))));
+                    Ok(())
+                }
             }
         }
     }
@@ -301,183 +681,587 @@ impl<'a> TrieDBMut<'a> {
     /// Remove auxiliary
     fn remove_aux(
         &mut self,
-        path: &NibbleSlice<'_>,
-        cur_node_hash: Option<H256>,
+        slot: &mut Option<NodeHandle>,
+        path: NibbleSlice<'_>,
         old_val: &mut Option<DBValue>,
-    ) -> crate::Result<Option<H256>> {
-        match cur_node_hash {
-            Some(hash) => {
-                let node_rlp = self.db.get(&hash).ok_or_else(|| TrieError::IncompleteDatabase(hash))?;
+    ) -> crate::Result<()> {
+        let handle = match slot {
+            Some(handle) => handle,
+            None => return Ok(()),
+        };
 
-                match RlpNode::decoded(&node_rlp) {
-                    Some(RlpNode::Leaf(partial, value)) => {
-                        if path == &partial {
-                            *old_val = Some(value.to_vec());
+        match self.resolve(handle)? {
+            OwnedNode::Leaf(partial, value) => {
+                if is_same_path(partial, &path) {
+                    *old_val = Some(mem::take(value));
+                    *slot = None;
+                }
+                Ok(())
+            }
+            OwnedNode::Branch(partial, children) => {
+                let common = common_prefix(partial, &path);
+                if common < partial.len() {
+                    return Ok(())
+                }
 
-                            Ok(None)
-                        } else {
-                            Ok(cur_node_hash)
-                        }
-                    }
-                    Some(RlpNode::Branch(partial, mut children)) => {
-                        if path.starts_with(&partial) {
-                            let new_path = path.mid(partial.len());
-                            children[new_path.at(0) as usize] =
-                                self.remove_aux(&new_path.mid(1), children[new_path.at(0) as usize], old_val)?;
-
-                            if children[new_path.at(0) as usize] == None {
-                                // Fix the node
-                                let child_count = children.iter().filter(|x| x.is_none()).count();
-
-                                match child_count {
-                                    16 => {
-                                        // Branch can be removed
-                                        Ok(None)
-                                    }
-                                    15 => {
-                                        // Transform the branch into Leaf
-                                        let index = children
-                                            .iter()
-                                            .position(Option::is_some)
-                                            .expect("Can not find leaf in the branch");
-                                        let new_leaf_hash = children[index].expect("Index is wrong");
-                                        let new_leaf_data = self
-                                            .db
-                                            .get(&new_leaf_hash)
-                                            .ok_or_else(|| TrieError::IncompleteDatabase(hash))?;
-                                        let new_leaf_node = RlpNode::decoded(&new_leaf_data);
-
-                                        match new_leaf_node {
-                                            None => Err(TrieError::IncompleteDatabase(hash)),
-                                            Some(RlpNode::Leaf(child_partial, child_value)) => {
-                                                let mut vec = partial.to_vec();
-                                                vec.push(index as u8);
-                                                vec.append(&mut child_partial.to_vec());
-
-                                                let (new_partial, offset) = NibbleSlice::from_vec(&vec);
-                                                let new_leaf = RlpNode::Leaf(
-                                                    NibbleSlice::new_offset(&new_partial, offset),
-                                                    child_value,
-                                                );
-                                                let node_rlp = RlpNode::encoded(new_leaf);
-                                                let new_hash = self.db.insert(&node_rlp);
-
-                                                Ok(Some(new_hash))
-                                            }
-                                            Some(RlpNode::Branch(child_partial, children)) => {
-                                                let mut vec = partial.to_vec();
-                                                vec.push(index as u8);
-                                                vec.append(&mut child_partial.to_vec());
-
-                                                let (new_partial, offset) = NibbleSlice::from_vec(&vec);
-                                                let new_branch = RlpNode::Branch(
-                                                    NibbleSlice::new_offset(&new_partial, offset),
-                                                    children,
-                                                );
-                                                let node_rlp = RlpNode::encoded(new_branch);
-                                                let new_hash = self.db.insert(&node_rlp);
-
-                                                Ok(Some(new_hash))
-                                            }
-                                        }
-                                    }
-                                    _ => {
-                                        let new_branch = RlpNode::Branch(partial, children);
-                                        let node_rlp = RlpNode::encoded(new_branch);
-                                        let new_hash = self.db.insert(&node_rlp);
-
-                                        Ok(Some(new_hash))
-                                    }
-                                }
-                            } else {
-                                let new_branch = RlpNode::Branch(partial, children);
-                                let node_rlp = RlpNode::encoded(new_branch);
-                                let new_hash = self.db.insert(&node_rlp);
-
-                                Ok(Some(new_hash))
+                let index = path.at(common) as usize;
+                self.remove_aux(&mut children[index], path.mid(common + 1), old_val)?;
+                if children[index].is_some() || old_val.is_none() {
+                    return Ok(())
+                }
+
+                // Fix the node
+                let mut remaining = children.iter().enumerate().filter(|(_, child)| child.is_some()).map(|(i, _)| i);
+                match (remaining.next(), remaining.next()) {
+                    // Branch can be removed
+                    (None, _) => *slot = None,
+                    // Merge the branch with its only child
+                    (Some(only), None) => {
+                        let mut child = children[only].take().expect("The index of an existing child");
+                        match self.resolve(&mut child) {
+                            Ok(child_node) => {
+                                let child_partial = child_node.partial_mut();
+                                let mut merged = mem::take(partial);
+                                merged.push(only as u8);
+                                merged.append(child_partial);
+                                *child_partial = merged;
+                            }
+                            Err(err) => {
+                                // Put back the removed leaf not to lose it
+                                children[only] = Some(child);
+                                let value = old_val.take().expect("The value of the removed leaf");
+                                children[index] = Some(NodeHandle::leaf(path.mid(common + 1), &value));
+                                return Err(err)
                             }
-                        } else {
-                            Ok(cur_node_hash)
                         }
+                        *slot = Some(child);
                     }
-                    None => Ok(cur_node_hash),
+                    // The branch still has two or more children
+                    _ => {}
                 }
+                Ok(())
             }
-            None => Ok(cur_node_hash),
         }
     }
-}
-
-impl<'a> fmt::Display for RlpNode<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            RlpNode::Leaf(partial, value) => writeln!(f, "Leaf - key({:?}), value({:?})", partial, value),
-            RlpNode::Branch(partial, children) => {
-                writeln!(f, "Branch - path({:?})", partial)?;
 
-                debug_assert_eq!(16, children.len());
-                for (i, child) in children.iter().enumerate() {
-                    writeln!(f, "child {} - hash({:?})", i, child)?;
-                }
+    /// Apply a change under `slot`, where the first `depth` nibbles of the path are already consumed.
+    fn apply_change(
+        &mut self,
+        slot: &mut Option<NodeHandle>,
+        depth: usize,
+        change: &BatchChange<'_>,
+        old_val: &mut Option<DBValue>,
+    ) -> crate::Result<()> {
+        let path = NibbleSlice::new(&change.path).mid(depth);
+        match (slot.as_mut(), change.value) {
+            (Some(handle), Some(value)) => self.insert_aux(handle, path, value, old_val),
+            (None, Some(value)) => {
+                *slot = Some(NodeHandle::leaf(path, value));
                 Ok(())
             }
+            (_, None) => self.remove_aux(slot, path, old_val),
         }
     }
-}
 
-impl<'a> Trie for TrieDBMut<'a> {
-    fn root(&self) -> &H256 {
-        self.root
-    }
+    /// Apply the `changes` sorted by their paths under `slot`, where the first `depth` nibbles of the paths are
+    /// already consumed. The changes under the same child of a branch are applied after walking to the child once.
+    fn apply_batch_aux(
+        &mut self,
+        slot: &mut Option<NodeHandle>,
+        depth: usize,
+        changes: &[BatchChange<'_>],
+        old_values: &mut [Option<DBValue>],
+    ) -> crate::Result<()> {
+        let handle = match slot {
+            Some(handle) if changes.len() > 1 => handle,
+            _ => {
+                for change in changes {
+                    self.apply_change(slot, depth, change, &mut old_values[change.index])?;
+                }
+                return Ok(())
+            }
+        };
+        let (partial, children) = match self.resolve(handle)? {
+            OwnedNode::Branch(partial, children) => (partial, children),
+            OwnedNode::Leaf(..) => {
+                for change in changes {
+                    self.apply_change(slot, depth, change, &mut old_values[change.index])?;
+                }
+                return Ok(())
+            }
+        };
+        // The changes which leave the partial path split the branch, so they are applied one by one.
+        if changes
+            .iter()
+            .any(|change| common_prefix(partial, &NibbleSlice::new(&change.path).mid(depth)) < partial.len())
+        {
+            for change in changes {
+                self.apply_change(slot, depth, change, &mut old_values[change.index])?;
+            }
+            return Ok(())
+        }
 
-    fn is_empty(&self) -> bool {
-        *self.root == BLAKE_NULL_RLP
+        let child_depth = depth + partial.len() + 1;
+        let mut rest = changes;
+        while let Some(first) = rest.first() {
+            let index = NibbleSlice::new(&first.path).at(child_depth - 1);
+            let count =
+                rest.iter().take_while(|change| NibbleSlice::new(&change.path).at(child_depth - 1) == index).count();
+            self.apply_batch_aux(&mut children[index as usize], child_depth, &rest[..count], old_values)?;
+            rest = &rest[count..];
+        }
+        self.fix_branch(slot)
     }
 
-    fn get(&self, key: &[u8]) -> crate::Result<Option<DBValue>> {
-        let t = TrieDB::try_new(self.db, self.root)?;
+    /// Apply `changes`, where `None` or an empty value removes the key, at once, in parallel threads if `parallel`.
+    fn apply_changes<I, K, V>(&mut self, changes: I, parallel: bool) -> crate::Result<Vec<Option<DBValue>>>
+    where
+        I: IntoIterator<Item = (K, Option<V>)>,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>, {
+        let changes: Vec<(K, Option<V>)> = changes.into_iter().collect();
+        let mut batch: Vec<BatchChange<'_>> = changes
+            .iter()
+            .enumerate()
+            .map(|(index, (key, value))| {
+                Ok(BatchChange {
+                    path: H::hash(key.as_ref())?,
+                    value: value.as_ref().map(AsRef::as_ref).filter(|value| !value.is_empty()),
+                    index,
+                })
+            })
+            .collect::<crate::Result<_>>()?;
+        // The sort is stable, so the changes of the same key are applied in the given order.
+        batch.sort_by_key(|change| change.path);
+
+        let mut old_values = vec![None; changes.len()];
+        let savepoint = self.savepoint();
+        let mut root_handle = self.root_handle.take();
+        let result = if parallel && self.recorder.is_none() {
+            self.apply_batch_parallel(&mut root_handle, &batch, &mut old_values)
+        } else {
+            self.apply_batch_aux(&mut root_handle, 0, &batch, &mut old_values)
+        };
+        self.root_handle = root_handle;
+        if let Err(err) = result {
+            self.rollback_to(savepoint);
+            return Err(err)
+        }
+        self.release(savepoint);
 
-        t.get(key)
+        if let Some(preimages) = self.preimages.as_mut() {
+            for change in batch.iter().filter(|change| change.value.is_some()) {
+                if !preimages.contains(&change.path) {
+                    preimages.emplace(change.path, changes[change.index].0.as_ref().to_vec());
+                }
+            }
+        }
+        Ok(old_values)
     }
 
-    fn is_complete(&self) -> bool {
-        TrieDB::try_new(self.db, self.root).map(|t| t.is_complete()).unwrap_or(false)
-    }
-}
+    /// Same as `apply_batch_aux()` on the root, but the changes under each child of the root branch are applied in
+    /// their own thread, unless there are fewer than `PARALLEL_CUTOFF` of them.
+    fn apply_batch_parallel(
+        &mut self,
+        slot: &mut Option<NodeHandle>,
+        changes: &[BatchChange<'_>],
+        old_values: &mut [Option<DBValue>],
+    ) -> crate::Result<()> {
+        let handle = match slot {
+            Some(handle) if changes.len() >= PARALLEL_CUTOFF => handle,
+            _ => return self.apply_batch_aux(slot, 0, changes, old_values),
+        };
+        let (partial, children) = match self.resolve(handle)? {
+            OwnedNode::Branch(partial, children)
+                if changes
+                    .iter()
+                    .all(|change| common_prefix(partial, &NibbleSlice::new(&change.path)) == partial.len()) =>
+            {
+                (partial, children)
+            }
+            _ => return self.apply_batch_aux(slot, 0, changes, old_values),
+        };
 
-impl<'a> TrieMut for TrieDBMut<'a> {
-    fn insert(&mut self, key: &[u8], value: &[u8]) -> crate::Result<Option<DBValue>> {
-        let path = blake256(key);
-        let mut old_val = None;
-        let cur_hash = *self.root;
-        *self.root = self.insert_aux(NibbleSlice::new(&path), value, Some(cur_hash), &mut old_val)?;
+        let depth = partial.len() + 1;
+        let mut groups = Vec::new();
+        let mut rest = changes;
+        while let Some(first) = rest.first() {
+            let index = NibbleSlice::new(&first.path).at(depth - 1) as usize;
+            let count =
+                rest.iter().take_while(|change| NibbleSlice::new(&change.path).at(depth - 1) as usize == index).count();
+            groups.push((index, children[index].take(), &rest[..count]));
+            rest = &rest[count..];
+        }
 
-        Ok(old_val)
+        let db: &dyn HashDB = &*self.db;
+        let outputs = thread::scope(|scope| {
+            let (large, small): (Vec<_>, Vec<_>) =
+                groups.into_iter().partition(|(_, _, changes)| changes.len() >= PARALLEL_CUTOFF);
+            let workers: Vec<_> = large
+                .into_iter()
+                .map(|(index, child, changes)| {
+                    (index, changes, scope.spawn(move |_| apply_in_worker::<H>(db, child, depth, changes)))
+                })
+                .collect();
+            let mut outputs: Vec<_> = small
+                .into_iter()
+                .map(|(index, child, changes)| (index, changes, apply_in_worker::<H>(db, child, depth, changes)))
+                .collect();
+            for (index, changes, worker) in workers {
+                outputs.push((index, changes, worker.join().expect("Applying changes never panics")));
+            }
+            outputs
+        })
+        .expect("Every worker is joined");
+
+        for (index, changes, output) in outputs {
+            let output = output?;
+            children[index] = output.slot;
+            self.obsoleted.extend(output.obsoleted);
+            for (change, old_value) in changes.iter().zip(output.old_values) {
+                old_values[change.index] = old_value;
+            }
+        }
+        self.fix_branch(slot)
     }
 
-    fn remove(&mut self, key: &[u8]) -> crate::Result<Option<DBValue>> {
-        let path = blake256(key);
-        let mut old_val = None;
-        let cur_hash = *self.root;
-
-        *self.root = match self.remove_aux(&NibbleSlice::new(&path), Some(cur_hash), &mut old_val)? {
-            Some(hash) => hash,
-            None => BLAKE_NULL_RLP,
+    /// Remove the branch in `slot` if it has no children, or merge it with its only child.
+    fn fix_branch(&mut self, slot: &mut Option<NodeHandle>) -> crate::Result<()> {
+        let handle = match slot {
+            Some(handle) => handle,
+            None => return Ok(()),
+        };
+        let (partial, children) = match self.resolve(handle)? {
+            OwnedNode::Branch(partial, children) => (partial, children),
+            OwnedNode::Leaf(..) => return Ok(()),
         };
 
-        Ok(old_val)
+        let mut remaining = children.iter().enumerate().filter(|(_, child)| child.is_some()).map(|(i, _)| i);
+        match (remaining.next(), remaining.next()) {
+            // Branch can be removed
+            (None, _) => *slot = None,
+            // Merge the branch with its only child
+            (Some(only), None) => {
+                let mut child = children[only].take().expect("The index of an existing child");
+                let child_partial = self.resolve(&mut child)?.partial_mut();
+                let mut merged = mem::take(partial);
+                merged.push(only as u8);
+                merged.append(child_partial);
+                *child_partial = merged;
+                *slot = Some(child);
+            }
+            // The branch still has two or more children
+            _ => {}
+        }
+        Ok(())
     }
-}
 
+    /// Update auxiliary. Returns whether a new value is written.
+    fn update_aux<F>(
+        &mut self,
+        slot: &mut Option<NodeHandle>,
+        path: NibbleSlice<'_>,
+        f: F,
+        old_val: &mut Option<DBValue>,
+    ) -> crate::Result<bool>
+    where
+        F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>>, {
+        let handle = match slot {
+            Some(handle) => handle,
+            None => {
+                let new_val = f(None);
+                if let Some(value) = &new_val {
+                    *slot = Some(NodeHandle::leaf(path, value));
+                }
+                return Ok(new_val.is_some())
+            }
+        };
 
-#[cfg(test)]
-mod tests {
-    use ccrypto::BLAKE_NULL_RLP;
-    use cdb::*;
-    use standardmap::*;
+        match self.resolve(handle)? {
+            OwnedNode::Leaf(partial, value) if is_same_path(partial, &path) => match f(Some(value)) {
+                Some(new_val) => {
+                    *old_val = Some(mem::replace(value, new_val));
+                    Ok(true)
+                }
+                None => {
+                    *old_val = Some(mem::take(value));
+                    *slot = None;
+                    Ok(false)
+                }
+            },
+            OwnedNode::Branch(partial, children) if common_prefix(partial, &path) == partial.len() => {
+                let common = partial.len();
+                let index = path.at(common) as usize;
+                let written = self.update_aux(&mut children[index], path.mid(common + 1), f, old_val)?;
+                if children[index].is_none() && old_val.is_some() {
+                    self.fix_branch(slot)?;
+                }
+                Ok(written)
+            }
+            // The key is not in the trie
+            _ => match f(None) {
+                Some(new_val) => {
+                    self.insert_aux(handle, path, &new_val, old_val)?;
+                    Ok(true)
+                }
+                None => Ok(false),
+            },
+        }
+    }
+
+    /// Write the encoded nodes to the database, and add them to `inserted`.
+    /// A node found in `obsoleted` is not modified after it is loaded, so it is neither written nor removed.
+    fn write_nodes(
+        &mut self,
+        nodes: Vec<(H256, Bytes)>,
+        obsoleted: &mut HashMap<H256, usize>,
+        inserted: &mut Vec<(H256, DBValue)>,
+    ) {
+        for (hash, node_rlp) in nodes {
+            match obsoleted.get_mut(&hash) {
+                Some(count) if *count > 0 => *count -= 1,
+                _ => {
+                    self.db.insert(&node_rlp);
+                    inserted.push((hash, node_rlp.clone()));
+                }
+            }
+            self.cache.insert(hash, node_rlp);
+        }
+    }
+
+    fn commit_root(&mut self) -> ChangeSet {
+        self.savepoints.clear();
+        let mut changes = ChangeSet::default();
+        let mut obsoleted = HashMap::new();
+        for hash in self.obsoleted.drain(..) {
+            *obsoleted.entry(hash).or_insert(0) += 1;
+        }
+
+        *self.root = match self.root_handle.take() {
+            Some(handle) => {
+                let mut nodes = Vec::new();
+                let hash = encode_nodes(&handle, &mut nodes);
+                self.write_nodes(nodes, &mut obsoleted, &mut changes.inserted);
+                self.root_handle = Some(NodeHandle::Hash(hash));
+                hash
+            }
+            None => BLAKE_NULL_RLP,
+        };
+
+        for (hash, count) in obsoleted {
+            for _ in 0..count {
+                if self.pruning {
+                    self.db.remove(&hash);
+                }
+                changes.obsoleted.push(hash);
+            }
+        }
+        changes
+    }
+
+    /// Same as `commit()`, but returns the nodes which are written and made unreachable.
+    pub fn commit_changeset(&mut self) -> ChangeSet {
+        self.commit_root()
+    }
+
+    /// Same as `apply_batch()` followed by `commit()`, but the changes under each child of the root branch are
+    /// applied and hashed in their own thread. The children with fewer than `PARALLEL_CUTOFF` changes are done in
+    /// the calling thread. Everything is done in the calling thread if the trie has a recorder.
+    /// Returns the new root, which is the same as the one made by applying the changes one by one.
+    pub fn apply_parallel<I, K, V>(&mut self, changes: I) -> crate::Result<H256>
+    where
+        I: IntoIterator<Item = (K, Option<V>)>,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>, {
+        self.apply_changes(changes, true)?;
+        self.commit();
+        Ok(*self.root)
+    }
+}
+
+impl<'a> fmt::Display for RlpNode<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RlpNode::Leaf(partial, value) => writeln!(f, "Leaf - key({:?}), value({:?})", partial, value),
+            RlpNode::Branch(partial, children) => {
+                writeln!(f, "Branch - path({:?})", partial)?;
+
+                debug_assert_eq!(16, children.len());
+                for (i, child) in children.iter().enumerate() {
+                    writeln!(f, "child {} - hash({:?})", i, child)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<'a, H: KeyHasher> Trie for TrieDBMut<'a, H> {
+    fn root(&self) -> &H256 {
+        match &self.root_handle {
+            Some(handle) => handle.hash(),
+            None => &BLAKE_NULL_RLP,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.root_handle.is_none()
+    }
+
+    fn get(&self, key: &[u8]) -> crate::Result<Option<DBValue>> {
+        let path = H::hash(key)?;
+
+        match &self.root_handle {
+            Some(handle) => self.get_aux(handle, NibbleSlice::new(&path)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_preimage(&self, hash: &H256) -> Option<Bytes> {
+        self.preimages.as_ref().and_then(|preimages| preimages.get(hash))
+    }
+
+    fn is_complete(&self) -> bool {
+        match &self.root_handle {
+            Some(handle) => self.is_complete_in_memory(handle),
+            None => true,
+        }
+    }
+}
+
+impl<'a, H: KeyHasher> IterableTrie for TrieDBMut<'a, H> {
+    fn iter(&self) -> crate::Result<TrieIterator<'_>> {
+        TrieIterator::new_with_overlay(self.db, self.pending_nodes(), self.root())
+    }
+
+    fn iter_with_preimages(&self) -> crate::Result<PreimageIterator<'_>> {
+        let preimages = self.preimages.as_ref().map(|preimages| &**preimages as &dyn HashDB);
+        Ok(self.iter()?.with_preimage_store(preimages))
+    }
+}
+
+impl<'a, H: KeyHasher> TrieMut for TrieDBMut<'a, H> {
+    fn insert(&mut self, key: &[u8], value: &[u8]) -> crate::Result<Option<DBValue>> {
+        if value.is_empty() {
+            return self.remove(key)
+        }
+
+        let path = H::hash(key)?;
+        let mut old_val = None;
+        let mut root_handle = self.root_handle.take();
+        let result = match &mut root_handle {
+            Some(handle) => self.insert_aux(handle, NibbleSlice::new(&path), value, &mut old_val),
+            None => {
+                root_handle = Some(NodeHandle::leaf(NibbleSlice::new(&path), value));
+                Ok(())
+            }
+        };
+        self.root_handle = root_handle;
+        result?;
+
+        if let Some(preimages) = self.preimages.as_mut() {
+            if !preimages.contains(&path) {
+                preimages.emplace(path, key.to_vec());
+            }
+        }
+
+        Ok(old_val)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> crate::Result<Option<DBValue>> {
+        let path = H::hash(key)?;
+        let mut old_val = None;
+        let mut root_handle = self.root_handle.take();
+        let result = self.remove_aux(&mut root_handle, NibbleSlice::new(&path), &mut old_val);
+        self.root_handle = root_handle;
+        result?;
+
+        Ok(old_val)
+    }
+
+    fn apply_batch<I, K, V>(&mut self, changes: I) -> crate::Result<Vec<Option<DBValue>>>
+    where
+        I: IntoIterator<Item = (K, Option<V>)>,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>, {
+        self.apply_changes(changes, false)
+    }
+
+    fn update<F>(&mut self, key: &[u8], f: F) -> crate::Result<Option<DBValue>>
+    where
+        F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>>, {
+        let path = H::hash(key)?;
+        let f = |old: Option<&[u8]>| f(old).filter(|value| !value.is_empty());
+        let mut old_val = None;
+        // Merging a branch after the removal can fail, so the trie is restored on error.
+        let savepoint = self.savepoint();
+        let mut root_handle = self.root_handle.take();
+        let result = self.update_aux(&mut root_handle, NibbleSlice::new(&path), f, &mut old_val);
+        self.root_handle = root_handle;
+        let written = match result {
+            Ok(written) => written,
+            Err(err) => {
+                self.rollback_to(savepoint);
+                return Err(err)
+            }
+        };
+        self.release(savepoint);
+
+        if written {
+            if let Some(preimages) = self.preimages.as_mut() {
+                if !preimages.contains(&path) {
+                    preimages.emplace(path, key.to_vec());
+                }
+            }
+        }
+        Ok(old_val)
+    }
+
+    fn commit(&mut self) {
+        self.commit_root();
+    }
+}
+
+impl<'a, H: KeyHasher> Drop for TrieDBMut<'a, H> {
+    fn drop(&mut self) {
+        self.commit();
+    }
+}
+
+impl<'a, H: KeyHasher> CryptoStructure for TrieDBMut<'a, H> {
+    fn make_proof(&self, key: &H256) -> crate::Result<(CryptoProofUnit, CryptoProof)> {
+        let (value, reversed_proof) = match &self.root_handle {
+            Some(handle) => self.make_proof_in_memory(handle, &NibbleSlice::new(key))?,
+            None => (None, Vec::new()),
+        };
+        let unit = CryptoProofUnit {
+            root: *self.root(),
+            key: *key,
+            value,
+        };
+        Ok((unit, CryptoProof(reversed_proof.into_iter().rev().collect())))
+    }
+
+    fn make_range_proof(&self, start: &H256, end: &H256) -> crate::Result<(Vec<(H256, Bytes)>, CryptoProof)> {
+        let start = NibbleSlice::new(start).to_vec();
+        let end = NibbleSlice::new(end).to_vec();
+        let mut items = Vec::new();
+        let mut proof = Vec::new();
+        if let Some(handle) = &self.root_handle {
+            self.make_range_proof_in_memory(handle, Vec::new(), (&start, &end), &mut items, &mut proof)?;
+        }
+        Ok((items, CryptoProof(proof)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ccrypto::BLAKE_NULL_RLP;
+    use cdb::*;
+    use standardmap::*;
 
     use crate::triehash::trie_root;
-    use crate::TrieMut;
+    use crate::{TrieDB, TrieMut};
 
     use super::*;
 
@@ -795,7 +1579,6 @@ mod tests {
     }
 
     #[test]
-    #[ignore]
     fn insert_empty() {
         let mut seed = H256::zero();
         let x = StandardMap {
@@ -824,6 +1607,64 @@ mod tests {
         assert_eq!(*t.root(), BLAKE_NULL_RLP);
     }
 
+    #[test]
+    fn empty_values_are_removed_everywhere() {
+        use crate::TrieBuilder;
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        use std::collections::BTreeMap;
+
+        let mut rng = StdRng::from_seed([23u8; 32]);
+        for _ in 0..10 {
+            let changes: Vec<([u8; 1], Vec<u8>)> = (0..200)
+                .map(|_| {
+                    let value = if rng.gen() {
+                        Vec::new()
+                    } else {
+                        rng.gen::<u32>().to_be_bytes().to_vec()
+                    };
+                    ([rng.gen::<u8>()], value)
+                })
+                .collect();
+            let expected = trie_root(changes.clone());
+
+            let mut db = MemoryDB::new();
+            let mut root = H256::zero();
+            let mut t = TrieDBMut::new(&mut db, &mut root);
+            for (key, value) in &changes {
+                t.insert(key, value).unwrap();
+            }
+            t.commit();
+            assert_eq!(*t.root(), expected);
+            drop(t);
+
+            let mut db = MemoryDB::new();
+            let mut root = H256::zero();
+            let mut t = TrieDBMut::new(&mut db, &mut root);
+            t.apply_batch(changes.iter().map(|(key, value)| (key, Some(value)))).unwrap();
+            t.commit();
+            assert_eq!(*t.root(), expected);
+            drop(t);
+
+            let mut db = MemoryDB::new();
+            let mut root = H256::zero();
+            let mut t = TrieDBMut::new(&mut db, &mut root);
+            for (key, value) in &changes {
+                t.update(key, |_| Some(value.clone())).unwrap();
+            }
+            t.commit();
+            assert_eq!(*t.root(), expected);
+            drop(t);
+
+            let sorted: BTreeMap<_, _> = changes.iter().map(|(key, value)| (blake256(key), value)).collect();
+            let mut db = MemoryDB::new();
+            let mut builder = TrieBuilder::new(&mut db);
+            for (key, value) in sorted {
+                builder.insert(&key, value).unwrap();
+            }
+            assert_eq!(builder.finish(), expected);
+        }
+    }
+
     #[test]
     fn return_old_values() {
         let mut seed = H256::zero();
@@ -849,4 +1690,767 @@ mod tests {
             assert!(t.remove(&key).unwrap().is_none());
         }
     }
+
+    #[test]
+    fn hash_on_demand() {
+        let is_hashed = |t: &TrieDBMut<'_>| match &t.root_handle {
+            Some(NodeHandle::InMemory(_, hash)) => hash.get().is_some(),
+            _ => unreachable!("The root is changed"),
+        };
+
+        let mut db = MemoryDB::new();
+        let mut root = H256::zero();
+        let mut t = TrieDBMut::new(&mut db, &mut root);
+        for i in 0..100u32 {
+            t.insert(&i.to_be_bytes(), &i.to_le_bytes()).unwrap();
+        }
+        assert!(!is_hashed(&t));
+        let expected = trie_root((0..100u32).map(|i| (i.to_be_bytes(), i.to_le_bytes())));
+        assert_eq!(*t.root(), expected);
+        assert!(is_hashed(&t));
+
+        t.insert(&0u32.to_be_bytes(), b"new").unwrap();
+        assert!(!is_hashed(&t));
+        t.remove(&0u32.to_be_bytes()).unwrap();
+        t.insert(&0u32.to_be_bytes(), &0u32.to_le_bytes()).unwrap();
+        assert_eq!(*t.root(), expected);
+    }
+
+    #[test]
+    fn commit_writes_only_reachable_nodes() {
+        let mut seed = H256::zero();
+        let x = StandardMap {
+            alphabet: Alphabet::Custom(b"@QWERTYUIOPASDFGHJKLZXCVBNM[/]^_".to_vec()),
+            min_key: 5,
+            journal_key: 0,
+            value_mode: ValueMode::Index,
+            count: 100,
+        }
+        .make_with(&mut seed);
+
+        let mut db = MemoryDB::new();
+        let mut root = H256::zero();
+        {
+            let mut t = TrieDBMut::new(&mut db, &mut root);
+            for (key, value) in &x {
+                t.insert(key, value).unwrap();
+            }
+            assert_eq!(t.get(&x[0].0).unwrap(), Some(x[0].1.clone()));
+            assert_eq!(*t.root(), trie_root(x.clone()));
+        }
+        assert_eq!(root, trie_root(x.clone()));
+
+        let t = TrieDB::try_new(&db, &root).unwrap();
+        let (_, proof) = t.make_range_proof(&H256::zero(), &H256::from([0xff; 32])).unwrap();
+        let keys = db.keys();
+        assert_eq!(keys.len(), proof.0.len());
+        assert!(keys.values().all(|rc| *rc == 1));
+    }
+
+    /// How many times each node is reachable from `root`, which is the reference count it should have.
+    fn reachable_nodes(db: &MemoryDB, root: &H256) -> HashMap<H256, i32> {
+        fn visit(db: &MemoryDB, hash: &H256, counts: &mut HashMap<H256, i32>) {
+            let node_rlp = db.get(hash).expect("A reachable node must be in the database");
+            *counts.entry(*hash).or_insert(0) += 1;
+            if let Some(RlpNode::Branch(_, children)) = RlpNode::decoded(&node_rlp) {
+                for child in children.iter().flatten() {
+                    visit(db, child, counts);
+                }
+            }
+        }
+
+        let mut counts = HashMap::new();
+        if *root != BLAKE_NULL_RLP {
+            visit(db, root, &mut counts);
+        }
+        counts
+    }
+
+    #[test]
+    fn pruning_keeps_only_reachable_nodes() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let mut rng = StdRng::from_seed([0u8; 32]);
+        let mut db = MemoryDB::new();
+        let mut root = BLAKE_NULL_RLP;
+
+        for _ in 0..20 {
+            {
+                let mut t = TrieDBMut::from_existing(&mut db, &mut root).unwrap().with_pruning();
+                for _ in 0..30 {
+                    let key = rng.gen::<u8>().to_be_bytes();
+                    match rng.gen_range(0, 4) {
+                        0 => {
+                            t.remove(&key).unwrap();
+                        }
+                        1 => {
+                            // Rewrite the same value
+                            if let Some(value) = t.get(&key).unwrap() {
+                                t.insert(&key, &value).unwrap();
+                            }
+                        }
+                        _ => {
+                            t.insert(&key, &rng.gen::<u16>().to_be_bytes()).unwrap();
+                        }
+                    }
+                    if rng.gen_range(0, 10) == 0 {
+                        t.commit();
+                    }
+                }
+            }
+            assert_eq!(db.keys(), reachable_nodes(&db, &root));
+        }
+
+        let mut t = TrieDBMut::from_existing(&mut db, &mut root).unwrap().with_pruning();
+        for key in 0..=255u8 {
+            t.remove(&key.to_be_bytes()).unwrap();
+        }
+        t.commit();
+        assert_eq!(*t.root(), BLAKE_NULL_RLP);
+        drop(t);
+        assert!(db.keys().is_empty());
+    }
+
+    #[test]
+    fn pruning_keeps_shared_subtrees() {
+        use crate::hasher::Identity;
+
+        // The subtrees under the children 1 and 2 of the root are the same.
+        let key = |first: u8, i: u8| {
+            let mut key = [i; 32];
+            key[0] = (first << 4) | i;
+            key
+        };
+        let mut db = MemoryDB::new();
+        let mut root = H256::zero();
+        {
+            let mut t = TrieDBMut::<Identity>::new_with_hasher(&mut db, &mut root);
+            for i in 0..4 {
+                t.insert(&key(1, i), b"value").unwrap();
+                t.insert(&key(2, i), b"value").unwrap();
+            }
+        }
+        let reachable = reachable_nodes(&db, &root);
+        assert!(reachable.values().any(|count| *count == 2));
+        assert_eq!(db.keys(), reachable);
+
+        {
+            let mut t = TrieDBMut::<Identity>::from_existing_with_hasher(&mut db, &mut root).unwrap().with_pruning();
+            t.insert(&key(1, 0), b"changed").unwrap();
+        }
+        assert_eq!(db.keys(), reachable_nodes(&db, &root));
+
+        // Load both subtrees, and change only one of them.
+        {
+            let mut t = TrieDBMut::<Identity>::from_existing_with_hasher(&mut db, &mut root).unwrap().with_pruning();
+            t.insert(&key(1, 0), b"value").unwrap();
+            t.insert(&key(2, 1), b"value").unwrap();
+            t.insert(&key(1, 1), b"changed").unwrap();
+        }
+        assert_eq!(db.keys(), reachable_nodes(&db, &root));
+        let t = TrieDB::<Identity>::try_new_with_hasher(&db, &root).unwrap();
+        assert!(t.is_complete());
+        assert_eq!(t.get(&key(2, 1)).unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn pruning_after_reverted_change() {
+        let mut db = MemoryDB::new();
+        let mut root = H256::zero();
+        {
+            let mut t = TrieDBMut::new(&mut db, &mut root);
+            for i in 0..100u8 {
+                t.insert(&[i], &[i]).unwrap();
+            }
+        }
+        let committed = db.keys();
+
+        {
+            let mut t = TrieDBMut::from_existing(&mut db, &mut root).unwrap().with_pruning();
+            t.insert(&[0], b"changed").unwrap();
+            t.remove(&[1]).unwrap();
+            t.insert(&[0], &[0]).unwrap();
+            t.insert(&[1], &[1]).unwrap();
+            let changes = t.commit_changeset();
+            assert_eq!(changes.inserted, Vec::new());
+            assert_eq!(changes.obsoleted, Vec::new());
+        }
+        assert_eq!(db.keys(), committed);
+        assert_eq!(db.keys(), reachable_nodes(&db, &root));
+    }
+
+    #[test]
+    fn apply_parallel_makes_same_root() {
+        let mut seed = H256::zero();
+        let x = StandardMap {
+            alphabet: Alphabet::Custom(b"@QWERTYUIOPASDFGHJKLZXCVBNM[/]^_".to_vec()),
+            min_key: 5,
+            journal_key: 0,
+            value_mode: ValueMode::Index,
+            count: 1000,
+        }
+        .make_with(&mut seed);
+        let (old, new) = x.split_at(500);
+
+        let mut db = MemoryDB::new();
+        let mut root = H256::zero();
+        populate_trie(&mut db, &mut root, old);
+        let mut parallel_db = db.clone();
+        let mut parallel_root = root;
+
+        // Replace a half of the old values, remove the other half, and add the new values.
+        let changes: Vec<_> = old
+            .iter()
+            .enumerate()
+            .map(|(i, (key, _))| {
+                (
+                    key.clone(),
+                    if i % 2 == 0 {
+                        Some(format!("new{}", i).into_bytes())
+                    } else {
+                        None
+                    },
+                )
+            })
+            .chain(new.iter().map(|(key, value)| (key.clone(), Some(value.clone()))))
+            .collect();
+        {
+            let mut t = TrieDBMut::from_existing(&mut db, &mut root).unwrap().with_pruning();
+            for (key, value) in &changes {
+                match value {
+                    Some(value) => t.insert(key, value).unwrap(),
+                    None => t.remove(key).unwrap(),
+                };
+            }
+        }
+
+        let mut t = TrieDBMut::from_existing(&mut parallel_db, &mut parallel_root).unwrap().with_pruning();
+        assert_eq!(t.apply_parallel(changes).unwrap(), root);
+        assert_eq!(*t.root(), root);
+        drop(t);
+        assert_eq!(parallel_db.keys(), db.keys());
+    }
+
+    /// A database which records the threads reading it.
+    struct ThreadRecordingDB {
+        db: MemoryDB,
+        readers: std::sync::Mutex<std::collections::HashSet<std::thread::ThreadId>>,
+    }
+
+    impl HashDB for ThreadRecordingDB {
+        fn keys(&self) -> HashMap<H256, i32> {
+            self.db.keys()
+        }
+
+        fn get(&self, key: &H256) -> Option<DBValue> {
+            self.readers.lock().unwrap().insert(std::thread::current().id());
+            self.db.get(key)
+        }
+
+        fn contains(&self, key: &H256) -> bool {
+            self.db.contains(key)
+        }
+
+        fn insert(&mut self, value: &[u8]) -> H256 {
+            self.db.insert(value)
+        }
+
+        fn emplace(&mut self, key: H256, value: DBValue) {
+            self.db.emplace(key, value)
+        }
+
+        fn remove(&mut self, key: &H256) {
+            self.db.remove(key)
+        }
+    }
+
+    #[test]
+    fn apply_parallel_uses_threads() {
+        let mut db = ThreadRecordingDB {
+            db: MemoryDB::new(),
+            readers: Default::default(),
+        };
+        let mut root = H256::zero();
+        let keys: Vec<_> = (0..4000u32).map(|i| i.to_be_bytes()).collect();
+        {
+            let mut t = TrieDBMut::new(&mut db, &mut root);
+            for key in &keys {
+                t.insert(key, b"old").unwrap();
+            }
+        }
+        let expected = trie_root(keys.iter().map(|key| (key, b"new")));
+
+        db.readers.lock().unwrap().clear();
+        let mut t = TrieDBMut::from_existing(&mut db, &mut root).unwrap();
+        let changes = keys.iter().map(|key| (key, Some(b"new")));
+        assert_eq!(t.apply_parallel(changes).unwrap(), expected);
+        drop(t);
+        // Each of the 16 children of the root has about 250 changes, which is above the cutoff.
+        assert!(db.readers.lock().unwrap().len() > 1);
+
+        // A small batch is applied in the calling thread.
+        db.readers.lock().unwrap().clear();
+        let mut t = TrieDBMut::from_existing(&mut db, &mut root).unwrap();
+        t.apply_parallel(keys.iter().take(PARALLEL_CUTOFF).map(|key| (key, Some(b"newer")))).unwrap();
+        drop(t);
+        let readers = db.readers.lock().unwrap();
+        assert_eq!(readers.iter().collect::<Vec<_>>(), vec![&std::thread::current().id()]);
+    }
+
+    #[test]
+    fn apply_batch_returns_old_values() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let mut rng = StdRng::from_seed([21u8; 32]);
+        let changes: Vec<([u8; 1], Option<[u8; 4]>)> = (0..300)
+            .map(|_| {
+                let key = [rng.gen::<u8>()];
+                let value = if rng.gen_range(0, 3) == 0 {
+                    None
+                } else {
+                    Some(rng.gen::<u32>().to_be_bytes())
+                };
+                (key, value)
+            })
+            .collect();
+        let (first, second) = changes.split_at(150);
+
+        let mut db = MemoryDB::new();
+        let mut root = BLAKE_NULL_RLP;
+        let mut batch_db = MemoryDB::new();
+        let mut batch_root = BLAKE_NULL_RLP;
+        for changes in &[first, second] {
+            let mut t = TrieDBMut::from_existing(&mut db, &mut root).unwrap();
+            let old_values: Vec<_> = changes
+                .iter()
+                .map(|(key, value)| match value {
+                    Some(value) => t.insert(key, value).unwrap(),
+                    None => t.remove(key).unwrap(),
+                })
+                .collect();
+            drop(t);
+
+            let mut t = TrieDBMut::from_existing(&mut batch_db, &mut batch_root).unwrap();
+            assert_eq!(t.apply_batch(changes.to_vec()).unwrap(), old_values);
+            t.commit();
+            assert_eq!(*t.root(), root);
+        }
+    }
+
+    #[test]
+    fn update() {
+        let mut db = MemoryDB::new();
+        let mut root = H256::zero();
+        let mut t = TrieDBMut::new(&mut db, &mut root);
+        for i in 0..10u32 {
+            t.insert(&i.to_be_bytes(), &i.to_be_bytes()).unwrap();
+        }
+
+        let add_one = |old: Option<&[u8]>| {
+            let balance = old.map(|old| u32::from_be_bytes([old[0], old[1], old[2], old[3]])).unwrap_or(0);
+            Some((balance + 1).to_be_bytes().to_vec())
+        };
+        assert_eq!(t.update(&3u32.to_be_bytes(), add_one).unwrap(), Some(3u32.to_be_bytes().to_vec()));
+        assert_eq!(t.update(&10u32.to_be_bytes(), add_one).unwrap(), None);
+        assert_eq!(t.update(&5u32.to_be_bytes(), |_| None).unwrap(), Some(5u32.to_be_bytes().to_vec()));
+        assert_eq!(t.update(&11u32.to_be_bytes(), |_| None).unwrap(), None);
+        t.commit();
+        let updated_root = *t.root();
+        drop(t);
+
+        let mut memdb = MemoryDB::new();
+        let mut root = H256::zero();
+        let mut t = TrieDBMut::new(&mut memdb, &mut root);
+        for i in 0..10u32 {
+            t.insert(&i.to_be_bytes(), &i.to_be_bytes()).unwrap();
+        }
+        t.insert(&3u32.to_be_bytes(), &4u32.to_be_bytes()).unwrap();
+        t.insert(&10u32.to_be_bytes(), &1u32.to_be_bytes()).unwrap();
+        t.remove(&5u32.to_be_bytes()).unwrap();
+        t.commit();
+        assert_eq!(*t.root(), updated_root);
+    }
+
+    /// A `TrieMut` which implements only the required methods.
+    struct MinimalTrie<'db>(TrieDBMut<'db>);
+
+    impl<'db> Trie for MinimalTrie<'db> {
+        fn root(&self) -> &H256 {
+            self.0.root()
+        }
+
+        fn get(&self, key: &[u8]) -> crate::Result<Option<DBValue>> {
+            self.0.get(key)
+        }
+
+        fn is_complete(&self) -> bool {
+            self.0.is_complete()
+        }
+    }
+
+    impl<'db> TrieMut for MinimalTrie<'db> {
+        fn insert(&mut self, key: &[u8], value: &[u8]) -> crate::Result<Option<DBValue>> {
+            self.0.insert(key, value)
+        }
+
+        fn remove(&mut self, key: &[u8]) -> crate::Result<Option<DBValue>> {
+            self.0.remove(key)
+        }
+    }
+
+    #[test]
+    fn default_apply_batch() {
+        let mut db = MemoryDB::new();
+        let mut root = H256::zero();
+        let mut t = MinimalTrie(TrieDBMut::new(&mut db, &mut root));
+        t.insert(b"foo", b"old").unwrap();
+        let changes = vec![(&b"foo"[..], Some(&b"new"[..])), (b"bar", Some(b"bar")), (b"foo", None)];
+        assert_eq!(t.apply_batch(changes).unwrap(), vec![Some(b"old".to_vec()), None, Some(b"new".to_vec())]);
+        assert_eq!(*t.root(), trie_root(vec![(b"bar", b"bar")]));
+        drop(t);
+
+        // Lose the leaf of the key 0, so that the second change fails after the first one is applied.
+        {
+            let mut t = TrieDBMut::new(&mut db, &mut root);
+            for i in 0..100u32 {
+                t.insert(&i.to_be_bytes(), &i.to_le_bytes()).unwrap();
+            }
+        }
+        let committed = root;
+        let (_, proof) = TrieDB::try_new(&db, &root).unwrap().make_proof(&blake256(0u32.to_be_bytes())).unwrap();
+        db.remove(&blake256(proof.0.last().unwrap()));
+
+        let mut t = MinimalTrie(TrieDBMut::from_existing(&mut db, &mut root).unwrap());
+        let changes = vec![(1u32.to_be_bytes(), Some(b"new")), (0u32.to_be_bytes(), Some(b"new"))];
+        assert!(t.apply_batch(changes).is_err());
+        assert_eq!(*t.root(), committed);
+        assert_eq!(t.get(&1u32.to_be_bytes()).unwrap(), Some(1u32.to_le_bytes().to_vec()));
+    }
+
+    /// A trie whose `remove` always fails, and whose `insert` fails with the value `fail`.
+    struct FailingTrie<'db>(TrieDBMut<'db>);
+
+    impl<'db> Trie for FailingTrie<'db> {
+        fn root(&self) -> &H256 {
+            self.0.root()
+        }
+
+        fn get(&self, key: &[u8]) -> crate::Result<Option<DBValue>> {
+            self.0.get(key)
+        }
+
+        fn is_complete(&self) -> bool {
+            self.0.is_complete()
+        }
+    }
+
+    impl<'db> TrieMut for FailingTrie<'db> {
+        fn insert(&mut self, key: &[u8], value: &[u8]) -> crate::Result<Option<DBValue>> {
+            if value == b"fail" {
+                return Err(TrieError::IncompleteDatabase(H256::zero()))
+            }
+            self.0.insert(key, value)
+        }
+
+        fn remove(&mut self, _key: &[u8]) -> crate::Result<Option<DBValue>> {
+            Err(TrieError::IncompleteDatabase(H256::from([0xff; 32])))
+        }
+    }
+
+    #[test]
+    fn default_apply_batch_reports_failed_rollback() {
+        let mut db = MemoryDB::new();
+        let mut root = H256::zero();
+        let mut t = FailingTrie(TrieDBMut::new(&mut db, &mut root));
+        t.insert(b"foo", b"old").unwrap();
+
+        // Undoing the change of `foo` succeeds.
+        let changes = vec![(&b"foo"[..], Some(&b"new"[..])), (b"bar", Some(b"fail"))];
+        assert_eq!(t.apply_batch(changes), Err(TrieError::IncompleteDatabase(H256::zero())));
+        assert_eq!(t.get(b"foo").unwrap(), Some(b"old".to_vec()));
+
+        // Undoing the new key `baz` fails, but `foo` is still undone.
+        let changes = vec![(&b"baz"[..], Some(&b"new"[..])), (b"foo", Some(b"new")), (b"bar", Some(b"fail"))];
+        assert_eq!(
+            t.apply_batch(changes),
+            Err(TrieError::FailedRollback(
+                Box::new(TrieError::IncompleteDatabase(H256::zero())),
+                Box::new(TrieError::IncompleteDatabase(H256::from([0xff; 32])))
+            ))
+        );
+        assert_eq!(t.get(b"foo").unwrap(), Some(b"old".to_vec()));
+        assert_eq!(t.get(b"baz").unwrap(), Some(b"new".to_vec()));
+    }
+
+    #[test]
+    fn default_update() {
+        let add_one = |old: Option<&[u8]>| {
+            let balance = old.map(|old| u32::from_be_bytes([old[0], old[1], old[2], old[3]])).unwrap_or(0);
+            Some((balance + 1).to_be_bytes().to_vec())
+        };
+
+        let mut db = MemoryDB::new();
+        let mut root = H256::zero();
+        let mut t = TrieDBMut::new(&mut db, &mut root);
+        for i in 0..10u32 {
+            t.insert(&i.to_be_bytes(), &i.to_be_bytes()).unwrap();
+        }
+        t.update(&3u32.to_be_bytes(), add_one).unwrap();
+        t.update(&10u32.to_be_bytes(), add_one).unwrap();
+        t.update(&5u32.to_be_bytes(), |_| None).unwrap();
+        t.update(&6u32.to_be_bytes(), |_| Some(vec![])).unwrap();
+        let updated_root = *t.root();
+        drop(t);
+
+        let mut db = MemoryDB::new();
+        let mut root = H256::zero();
+        let mut t = MinimalTrie(TrieDBMut::new(&mut db, &mut root));
+        for i in 0..10u32 {
+            t.insert(&i.to_be_bytes(), &i.to_be_bytes()).unwrap();
+        }
+        assert_eq!(t.update(&3u32.to_be_bytes(), add_one).unwrap(), Some(3u32.to_be_bytes().to_vec()));
+        assert_eq!(t.update(&10u32.to_be_bytes(), add_one).unwrap(), None);
+        assert_eq!(t.update(&5u32.to_be_bytes(), |_| None).unwrap(), Some(5u32.to_be_bytes().to_vec()));
+        assert_eq!(t.update(&6u32.to_be_bytes(), |_| Some(vec![])).unwrap(), Some(6u32.to_be_bytes().to_vec()));
+        assert_eq!(t.update(&11u32.to_be_bytes(), |_| None).unwrap(), None);
+        assert_eq!(*t.root(), updated_root);
+    }
+
+    #[test]
+    fn apply_batch_is_atomic() {
+        let mut db = MemoryDB::new();
+        let mut root = H256::zero();
+        {
+            let mut t = TrieDBMut::new(&mut db, &mut root);
+            for i in 0..100u32 {
+                t.insert(&i.to_be_bytes(), &i.to_le_bytes()).unwrap();
+            }
+        }
+        // Lose the leaf of the key 0.
+        let (_, proof) = TrieDB::try_new(&db, &root).unwrap().make_proof(&blake256(0u32.to_be_bytes())).unwrap();
+        db.remove(&blake256(proof.0.last().unwrap()));
+
+        let mut t = TrieDBMut::from_existing(&mut db, &mut root).unwrap();
+        let changes: Vec<_> = (0..100u32).map(|i| (i.to_be_bytes(), Some(b"new".to_vec()))).collect();
+        assert!(t.apply_batch(changes).is_err());
+        for i in 1..100u32 {
+            assert_eq!(t.get(&i.to_be_bytes()).unwrap(), Some(i.to_le_bytes().to_vec()));
+        }
+        assert_eq!(t.apply_batch(vec![(1u32.to_be_bytes(), None::<Vec<u8>>)]).unwrap(), vec![Some(
+            1u32.to_le_bytes().to_vec()
+        )]);
+        assert_eq!(t.get(&1u32.to_be_bytes()).unwrap(), None);
+    }
+
+    #[test]
+    fn rollback_to_savepoint() {
+        let mut seed = H256::zero();
+        let x = StandardMap {
+            alphabet: Alphabet::Custom(b"@QWERTYUIOPASDFGHJKLZXCVBNM[/]^_".to_vec()),
+            min_key: 5,
+            journal_key: 0,
+            value_mode: ValueMode::Index,
+            count: 100,
+        }
+        .make_with(&mut seed);
+        let (old, new) = x.split_at(50);
+
+        let mut db = MemoryDB::new();
+        let mut root = H256::zero();
+        populate_trie(&mut db, &mut root, old);
+        let committed_keys = db.keys();
+
+        let mut t = TrieDBMut::from_existing(&mut db, &mut root).unwrap().with_pruning();
+        let savepoint = t.savepoint();
+        for (key, value) in new {
+            t.insert(key, value).unwrap();
+        }
+        let inner = t.savepoint();
+        for (key, _) in old {
+            t.remove(key).unwrap();
+        }
+        assert_eq!(t.get(&old[0].0).unwrap(), None);
+
+        assert!(t.rollback_to(inner));
+        assert_eq!(t.get(&old[0].0).unwrap(), Some(old[0].1.clone()));
+        assert_eq!(t.get(&new[0].0).unwrap(), Some(new[0].1.clone()));
+
+        assert!(t.rollback_to(savepoint));
+        assert_eq!(t.get(&new[0].0).unwrap(), None);
+        t.commit();
+        assert_eq!(*t.root(), trie_root(old.to_vec()));
+        drop(t);
+        assert_eq!(db.keys(), committed_keys);
+
+        let mut t = TrieDBMut::from_existing(&mut db, &mut root).unwrap().with_pruning();
+        let savepoint = t.savepoint();
+        for (key, value) in new {
+            t.insert(key, value).unwrap();
+        }
+        assert!(t.release(savepoint));
+        t.commit();
+        assert_eq!(*t.root(), trie_root(x.clone()));
+    }
+
+    #[test]
+    fn rollback_after_commit() {
+        let mut db = MemoryDB::new();
+        let mut root = H256::zero();
+        let mut t = TrieDBMut::new(&mut db, &mut root);
+        let savepoint = t.savepoint();
+        t.insert(b"foo", b"bar").unwrap();
+        t.commit();
+        assert!(!t.rollback_to(savepoint));
+        assert_eq!(t.get(b"foo").unwrap(), Some(b"bar".to_vec()));
+    }
+
+    #[test]
+    fn savepoint_of_another_trie() {
+        let mut db = MemoryDB::new();
+        let mut root = H256::zero();
+        let mut other_db = MemoryDB::new();
+        let mut other_root = H256::zero();
+        let mut t = TrieDBMut::new(&mut db, &mut root);
+        let mut other = TrieDBMut::new(&mut other_db, &mut other_root);
+
+        // Both tries have made the same number of savepoints.
+        let savepoint = t.savepoint();
+        let other_savepoint = other.savepoint();
+        t.insert(b"foo", b"bar").unwrap();
+        assert!(!t.rollback_to(other_savepoint));
+        assert_eq!(t.get(b"foo").unwrap(), Some(b"bar".to_vec()));
+
+        assert!(t.release(savepoint));
+        let savepoint = t.savepoint();
+        assert!(!other.release(savepoint));
+    }
+
+    #[test]
+    fn changeset_replays_commit() {
+        let mut seed = H256::zero();
+        let x = StandardMap {
+            alphabet: Alphabet::Custom(b"@QWERTYUIOPASDFGHJKLZXCVBNM[/]^_".to_vec()),
+            min_key: 5,
+            journal_key: 0,
+            value_mode: ValueMode::Index,
+            count: 200,
+        }
+        .make_with(&mut seed);
+        let (old, new) = x.split_at(100);
+
+        let mut db = MemoryDB::new();
+        let mut root = H256::zero();
+        populate_trie(&mut db, &mut root, old);
+        let mut journal_db = db.clone();
+
+        let mut t = TrieDBMut::from_existing(&mut db, &mut root).unwrap();
+        for (key, _) in &old[..50] {
+            t.remove(key).unwrap();
+        }
+        for (key, value) in new {
+            t.insert(key, value).unwrap();
+        }
+        let changes = t.commit_changeset();
+        assert_eq!(t.commit_changeset(), ChangeSet::default());
+        drop(t);
+
+        for (hash, node_rlp) in &changes.inserted {
+            assert_eq!(journal_db.insert(node_rlp), *hash);
+        }
+        for hash in &changes.obsoleted {
+            journal_db.remove(hash);
+        }
+        assert_eq!(journal_db.keys(), reachable_nodes(&db, &root));
+        // Without pruning, the obsoleted nodes are left in the database.
+        assert!(changes.obsoleted.iter().all(|hash| db.contains(hash)));
+    }
+
+    #[test]
+    fn make_proof_of_uncommitted_state() {
+        use crate::proof::{verify, verify_range};
+
+        let mut seed = H256::zero();
+        let x = StandardMap {
+            alphabet: Alphabet::Custom(b"@QWERTYUIOPASDFGHJKLZXCVBNM[/]^_".to_vec()),
+            min_key: 5,
+            journal_key: 0,
+            value_mode: ValueMode::Index,
+            count: 100,
+        }
+        .make_with(&mut seed);
+
+        let mut db = MemoryDB::new();
+        let mut root = H256::zero();
+        let mut t = populate_trie(&mut db, &mut root, &x);
+        for (key, value) in &x {
+            let (unit, proof) = t.make_proof(&blake256(key)).unwrap();
+            assert_eq!(unit.value.as_ref(), Some(value));
+            assert!(verify(&proof, &unit));
+        }
+
+        let removed = blake256(&x[0].0);
+        t.remove(&x[0].0).unwrap();
+        let (unit, proof) = t.make_proof(&removed).unwrap();
+        assert_eq!(unit.value, None);
+        assert_eq!(unit.root, trie_root(x[1..].to_vec()));
+        assert!(verify(&proof, &unit));
+
+        let (start, end) = (H256::zero(), H256::from([0xff; 32]));
+        let (items, proof) = t.make_range_proof(&start, &end).unwrap();
+        assert_eq!(items.len(), x.len() - 1);
+        assert!(verify_range(t.root(), &start, &end, &items, &proof));
+
+        assert_eq!(t.iter().unwrap().collect::<crate::Result<Vec<_>>>().unwrap(), items);
+        assert!(t.is_complete());
+    }
+
+    #[test]
+    fn proofs_of_uncommitted_state_match_committed_ones() {
+        let mut db = MemoryDB::new();
+        let mut root = H256::zero();
+        {
+            let mut t = TrieDBMut::new(&mut db, &mut root);
+            for i in 0..200u32 {
+                t.insert(&i.to_be_bytes(), &i.to_le_bytes()).unwrap();
+            }
+        }
+
+        // Only a few subtrees are loaded, and the others stay in the database.
+        let mut new_root = root;
+        let mut t = TrieDBMut::from_existing(&mut db, &mut new_root).unwrap();
+        for i in 0..10u32 {
+            t.insert(&(i * 3).to_be_bytes(), b"new").unwrap();
+            t.remove(&(i * 7 + 1).to_be_bytes()).unwrap();
+        }
+        let keys: Vec<_> = (0..250u32).map(|i| blake256(i.to_be_bytes())).collect();
+        let proofs: Vec<_> = keys.iter().map(|key| t.make_proof(key).unwrap()).collect();
+        let (start, end) = (H256::from([0x40; 32]), H256::from([0xc0; 32]));
+        let range_proof = t.make_range_proof(&start, &end).unwrap();
+        assert!(t.is_complete());
+        drop(t);
+
+        let t = TrieDB::try_new(&db, &new_root).unwrap();
+        for (key, proof) in keys.iter().zip(proofs) {
+            assert_eq!(t.make_proof(key).unwrap(), proof);
+        }
+        assert_eq!(t.make_range_proof(&start, &end).unwrap(), range_proof);
+    }
+
+    #[test]
+    fn is_complete_checks_unloaded_subtrees() {
+        let mut db = MemoryDB::new();
+        let mut root = H256::zero();
+        {
+            let mut t = TrieDBMut::new(&mut db, &mut root);
+            for i in 0..100u32 {
+                t.insert(&i.to_be_bytes(), &i.to_le_bytes()).unwrap();
+            }
+        }
+        let (_, proof) = TrieDB::try_new(&db, &root).unwrap().make_proof(&blake256(0u32.to_be_bytes())).unwrap();
+        db.remove(&blake256(proof.0.last().unwrap()));
+
+        let mut t = TrieDBMut::from_existing(&mut db, &mut root).unwrap();
+        t.insert(&1u32.to_be_bytes(), b"new").unwrap();
+        assert!(!t.is_complete());
+    }
 }
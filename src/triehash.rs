@@ -18,6 +18,7 @@
 //!
 //! This module should be used to generate trie root hash.
 
+use crate::hasher::{Blake256, KeyHasher};
 use ccrypto::blake256;
 use primitives::H256;
 use rlp::RlpStream;
@@ -29,24 +30,38 @@ fn shared_prefix_len<T: Eq>(first: &[T], second: &[T]) -> usize {
     (0..len).take_while(|&i| first[i] == second[i]).count()
 }
 
-/// Generates a trie root hash for a vector of key-values
+/// Generates a trie root hash for a vector of key-values.
+/// A key with an empty value is absent from the trie, as `TrieMut::insert` removes it.
 pub fn trie_root<I, A, B>(input: I) -> H256
 where
     I: IntoIterator<Item = (A, B)>,
     A: AsRef<[u8]> + Ord,
     B: AsRef<[u8]>, {
-    // Make key into hash value, which is blake256(key)
-    let gen_input: Vec<_> = input.into_iter().map(|(k, v)| (blake256(k), v)).collect();
+    trie_root_with_hasher::<Blake256, _, _, _>(input).expect("Blake256 accepts every key")
+}
+
+/// Generates a trie root hash for a vector of key-values, whose keys are hashed with `H`.
+/// Returns an error if `H` rejects a key.
+pub fn trie_root_with_hasher<H, I, A, B>(input: I) -> crate::Result<H256>
+where
+    H: KeyHasher,
+    I: IntoIterator<Item = (A, B)>,
+    A: AsRef<[u8]> + Ord,
+    B: AsRef<[u8]>, {
+    // Make key into hash value, which is H::hash(key)
+    let gen_input: Vec<_> =
+        input.into_iter().map(|(k, v)| H::hash(k.as_ref()).map(|k| (k, v))).collect::<crate::Result<_>>()?;
     let gen_input: Vec<_> = gen_input
 		// first put elements into btree to sort them and to remove duplicates
 		.into_iter()
 		.collect::<BTreeMap<_, _>>()
 		// then move them to a vector
 		.into_iter()
+		.filter(|(_, v)| !v.as_ref().is_empty())
 		.map(|(k, v)| (as_nibbles(k.as_ref()), v) )
 		.collect();
 
-    gen_trie_root(&gen_input)
+    Ok(gen_trie_root(&gen_input))
 }
 
 fn gen_trie_root<A: AsRef<[u8]>, B: AsRef<[u8]>>(input: &[(A, B)]) -> H256 {
@@ -224,6 +239,20 @@ mod tests {
         );
     }
 
+    #[test]
+    fn triehash_empty_values() {
+        assert_eq!(trie_root(vec![(vec![0x01u8, 0x23], vec![])]), ccrypto::BLAKE_NULL_RLP);
+        assert_eq!(
+            trie_root(vec![
+                (vec![0x01u8, 0x23], vec![0x01u8, 0x23]),
+                (vec![0x81u8, 0x23], vec![]),
+                (vec![0xf1u8, 0x23], vec![0xf1u8, 0x23]),
+                (vec![0x01u8, 0x23], vec![]),
+            ]),
+            trie_root(vec![(vec![0xf1u8, 0x23], vec![0xf1u8, 0x23])])
+        );
+    }
+
     #[test]
     fn shared_prefix() {
         let a = vec![1, 2, 3, 4, 5, 6];